@@ -0,0 +1,1129 @@
+//! Reward accrual shared by every instruction that touches a farm's crops.
+//!
+//! The functions here work on plain snapshots of a farm and a stake, so the same math runs
+//! on-chain, in unit tests and in off-chain clients that want to preview rewards.
+
+use crate::error::FarmError;
+
+pub const CROP_COUNT: usize = 8;
+
+/// How many payouts a vesting record can hold at once, each vesting on its own schedule.
+pub const VESTING_TRANCHES: usize = 4;
+
+/// Fixed-point scale applied to `crop_rewards_per_token`.
+pub const PRECISION_SCALER: u128 = 1 << 63;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The locks a stake can choose from: how long its tokens stay locked, in seconds, and the weight
+/// they earn rewards with meanwhile, in basis points of `amount_staked`.
+pub const LOCK_TIERS: [(u64, u64); 4] = [
+    (0, 10_000),
+    (30 * DAY, 12_500),
+    (90 * DAY, 15_000),
+    (180 * DAY, 20_000),
+];
+
+/// The parts of a `Farm` that reward accrual reads and writes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FarmRewards {
+    pub total_staked_amount: u64,
+    pub total_weighted_stake: u64,
+    pub stakers: u64,
+    pub crop_id: [u64; CROP_COUNT],
+    pub crop_rewards_per_second: [u64; CROP_COUNT],
+    pub crop_rewards_per_token: [u128; CROP_COUNT],
+    pub crop_end_date: [u64; CROP_COUNT],
+    pub crop_created_at: [u64; CROP_COUNT],
+    pub crop_start_at: [u64; CROP_COUNT],
+    pub crop_last_updated_at: [u64; CROP_COUNT],
+    pub crop_stakers_finished: [u64; CROP_COUNT],
+    pub crop_stakers_owed: [u64; CROP_COUNT],
+    pub crop_funded: [u64; CROP_COUNT],
+    pub crop_allocated: [u64; CROP_COUNT],
+    pub archived_crop_id: [u64; CROP_COUNT],
+    pub archived_rewards_per_token: [u128; CROP_COUNT],
+    pub archived_stakers_pending: [u64; CROP_COUNT],
+}
+
+/// The parts of a `Stake` that reward settlement reads and writes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StakeRewards {
+    pub amount_staked: u64,
+    pub weighted_stake: u64,
+    pub lock_tier: u8,
+    pub lock_until: u64,
+    pub last_updated_at: u64,
+    pub crop_id: [u64; CROP_COUNT],
+    pub reward_debt: [u128; CROP_COUNT],
+    pub amount_owed: [u64; CROP_COUNT],
+    pub crop_finished: [bool; CROP_COUNT],
+    pub archived_owed: [u64; CROP_COUNT],
+}
+
+/// The parts of a `Vesting` that reward vesting reads and writes. Each tranche's `vesting_amount`
+/// vests linearly from its `vested_until` to its `vesting_until`, moving into `vested_amount` as it
+/// does.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VestingSchedule {
+    pub vesting_amount: [u64; VESTING_TRANCHES],
+    pub vested_amount: u64,
+    pub vested_until: [u64; VESTING_TRANCHES],
+    pub vesting_until: [u64; VESTING_TRANCHES],
+}
+
+/// Advances every active crop's `crop_rewards_per_token` up to `now`, capped at the crop's end
+/// date, and adds what was emitted to `crop_allocated`. Time during which nothing is staked moves
+/// the crop forward without emitting rewards, and crops scheduled to start later are left alone.
+pub fn accrue_farm(farm: &mut FarmRewards, now: u64) -> Result<(), FarmError> {
+    for i in 0..CROP_COUNT {
+        // Crop not active
+        if farm.crop_created_at[i] == 0 {
+            continue;
+        }
+
+        // Crop not started yet
+        if now < farm.crop_start_at[i] {
+            continue;
+        }
+
+        // Crop already ended
+        if farm.crop_last_updated_at[i] >= farm.crop_end_date[i] {
+            continue;
+        }
+
+        let current_time = now.min(farm.crop_end_date[i]);
+
+        if current_time <= farm.crop_last_updated_at[i] {
+            continue;
+        }
+
+        if farm.total_weighted_stake > 0 {
+            let time_diff = current_time - farm.crop_last_updated_at[i];
+            let rewards_per_token = u128::from(time_diff)
+                .checked_mul(u128::from(farm.crop_rewards_per_second[i]))
+                .and_then(|rewards| rewards.checked_mul(PRECISION_SCALER))
+                .ok_or(FarmError::MathOverflow)?
+                / u128::from(farm.total_weighted_stake);
+
+            farm.crop_rewards_per_token[i] = farm.crop_rewards_per_token[i]
+                .checked_add(rewards_per_token)
+                .ok_or(FarmError::MathOverflow)?;
+
+            farm.crop_allocated[i] = time_diff
+                .checked_mul(farm.crop_rewards_per_second[i])
+                .and_then(|emitted| farm.crop_allocated[i].checked_add(emitted))
+                .ok_or(FarmError::MathOverflow)?;
+        }
+
+        farm.crop_last_updated_at[i] = current_time;
+    }
+
+    Ok(())
+}
+
+/// Switches the crop in slot `index` to `rewards_per_second` from `now`, or from its start if that
+/// is later, and moves its end date to when the rewards not yet allocated to stakers run out. The
+/// farm must already be accrued to `now`.
+pub fn reschedule_crop(
+    farm: &mut FarmRewards,
+    index: usize,
+    now: u64,
+    rewards_per_second: u64,
+) -> Result<(), FarmError> {
+    if rewards_per_second == 0 {
+        return Err(FarmError::ZeroRewardRate);
+    }
+
+    let unallocated = farm.crop_funded[index]
+        .checked_sub(farm.crop_allocated[index])
+        .ok_or(FarmError::MathOverflow)?;
+
+    farm.crop_rewards_per_second[index] = rewards_per_second;
+    farm.crop_end_date[index] = now
+        .max(farm.crop_start_at[index])
+        .checked_add(unallocated / rewards_per_second)
+        .ok_or(FarmError::MathOverflow)?;
+
+    Ok(())
+}
+
+/// Moves everything the stake has earned since its last settlement into `amount_owed`. The farm
+/// must already be accrued to the current time and the stake synced with `sync_crops`; slots the
+/// stake has not synced yet are left alone.
+pub fn settle_stake(farm: &FarmRewards, stake: &mut StakeRewards) -> Result<(), FarmError> {
+    if stake.amount_staked == 0 {
+        return Ok(());
+    }
+
+    for i in 0..CROP_COUNT {
+        if farm.crop_created_at[i] == 0 || stake.crop_id[i] != farm.crop_id[i] {
+            continue;
+        }
+
+        let pending_rewards =
+            accrued_per_stake(stake.weighted_stake, farm.crop_rewards_per_token[i])?
+                .checked_sub(stake.reward_debt[i])
+                .ok_or(FarmError::MathOverflow)?
+                / PRECISION_SCALER;
+
+        stake.amount_owed[i] = u64::try_from(pending_rewards)
+            .ok()
+            .and_then(|pending| stake.amount_owed[i].checked_add(pending))
+            .ok_or(FarmError::MathOverflow)?;
+    }
+
+    Ok(())
+}
+
+/// Settles a stake whose lock has run out since it was last settled at `last_updated_at`, counting
+/// its boost only up to `lock_until`. Past indices are not kept, so the boosted share of each crop
+/// is prorated over the time the crop emitted, which is exact while the crop's rate and the
+/// farm's weight stay the same. The boost the stake earned past `lock_until` is taken off
+/// `crop_allocated`, so it goes back to the crop's unallocated rewards instead of being stranded
+/// in the vault. Call before `settle_stake`, which then has nothing left to add, and drop the
+/// weight with `update_weight` afterwards.
+pub fn settle_expired_lock(
+    farm: &mut FarmRewards,
+    stake: &mut StakeRewards,
+    now: u64,
+) -> Result<(), FarmError> {
+    if stake.lock_tier == 0
+        || stake.weighted_stake == 0
+        || now < stake.lock_until
+        || stake.last_updated_at >= stake.lock_until
+    {
+        return Ok(());
+    }
+
+    for i in 0..CROP_COUNT {
+        if farm.crop_created_at[i] == 0 || stake.crop_id[i] != farm.crop_id[i] {
+            continue;
+        }
+
+        let accrued = accrued_per_stake(stake.weighted_stake, farm.crop_rewards_per_token[i])?;
+        let pending = accrued
+            .checked_sub(stake.reward_debt[i])
+            .ok_or(FarmError::MathOverflow)?
+            / PRECISION_SCALER;
+
+        let from = stake.last_updated_at.max(farm.crop_start_at[i]);
+        let to = now.min(farm.crop_end_date[i]);
+
+        // Without any emission in the window there is no boost to take back
+        let earned = if to > from {
+            let base = pending
+                .checked_mul(u128::from(stake.amount_staked))
+                .and_then(|base| base.checked_div(u128::from(stake.weighted_stake)))
+                .ok_or(FarmError::MathOverflow)?;
+            let boosted = u128::from(stake.lock_until.clamp(from, to) - from);
+
+            pending
+                .checked_sub(base)
+                .and_then(|boost| boost.checked_mul(boosted))
+                .and_then(|boost| boost.checked_div(u128::from(to - from)))
+                .and_then(|boost| boost.checked_add(base))
+                .ok_or(FarmError::MathOverflow)?
+        } else {
+            pending
+        };
+
+        // At most `pending`, which the stake could have been paid in full
+        let forfeited = u64::try_from(pending - earned).map_err(|_| FarmError::MathOverflow)?;
+
+        stake.amount_owed[i] = u64::try_from(earned)
+            .ok()
+            .and_then(|earned| stake.amount_owed[i].checked_add(earned))
+            .ok_or(FarmError::MathOverflow)?;
+        stake.reward_debt[i] = accrued;
+        farm.crop_allocated[i] = farm.crop_allocated[i]
+            .checked_sub(forfeited)
+            .ok_or(FarmError::MathOverflow)?;
+    }
+
+    Ok(())
+}
+
+/// Rebases the stake's `reward_debt` on the farm's current indices. Call after `weighted_stake`
+/// changes so that the next settlement only pays for rewards earned from here on.
+pub fn update_reward_debt(farm: &FarmRewards, stake: &mut StakeRewards) -> Result<(), FarmError> {
+    for i in 0..CROP_COUNT {
+        stake.reward_debt[i] =
+            accrued_per_stake(stake.weighted_stake, farm.crop_rewards_per_token[i])?;
+    }
+
+    Ok(())
+}
+
+/// Locks the stake into `LOCK_TIERS[tier]` from `now`. A stake that is still locked can be locked
+/// again for at least as long a tier, which restarts its lock, but not for a shorter one.
+pub fn lock_stake(stake: &mut StakeRewards, tier: u8, now: u64) -> Result<(), FarmError> {
+    let (duration, _) = LOCK_TIERS
+        .get(usize::from(tier))
+        .ok_or(FarmError::InvalidLockTier)?;
+
+    if tier < stake.lock_tier && now < stake.lock_until {
+        return Err(FarmError::LockTooShort);
+    }
+
+    stake.lock_tier = tier;
+    stake.lock_until = now.checked_add(*duration).ok_or(FarmError::MathOverflow)?;
+
+    Ok(())
+}
+
+/// Sets the stake's `weighted_stake` from `amount_staked` and its lock, moving the farm's
+/// `total_weighted_stake` with it. A lock that has run out by `now` drops back to the base tier.
+/// The stake must already be settled, and its reward debt updated afterwards.
+pub fn update_weight(
+    farm: &mut FarmRewards,
+    stake: &mut StakeRewards,
+    now: u64,
+) -> Result<(), FarmError> {
+    if now >= stake.lock_until {
+        stake.lock_tier = 0;
+    }
+
+    let (_, weight_bps) = LOCK_TIERS[usize::from(stake.lock_tier)];
+    let weighted_stake =
+        u64::try_from(u128::from(stake.amount_staked) * u128::from(weight_bps) / 10_000)
+            .map_err(|_| FarmError::MathOverflow)?;
+
+    farm.total_weighted_stake = farm
+        .total_weighted_stake
+        .checked_sub(stake.weighted_stake)
+        .and_then(|total| total.checked_add(weighted_stake))
+        .ok_or(FarmError::MathOverflow)?;
+
+    stake.weighted_stake = weighted_stake;
+
+    Ok(())
+}
+
+/// Points any of the stake's crop slots that still refer to a removed crop at the crop now in that
+/// slot.
+///
+/// If the removed crop was archived and the stake still had rewards coming from it, those rewards
+/// are settled against the archived index and moved to `archived_owed`, where they stay claimable
+/// through `claim_archived`. A stake with nothing to claim is released from the archive here.
+pub fn sync_crops(farm: &mut FarmRewards, stake: &mut StakeRewards) -> Result<(), FarmError> {
+    for i in 0..CROP_COUNT {
+        if stake.crop_id[i] == farm.crop_id[i] {
+            continue;
+        }
+
+        let archived_id = farm.archived_crop_id[i];
+
+        if archived_id != 0 && stake.crop_id[i] <= archived_id {
+            // A stake still on the archived crop settles from where it left off. One that is
+            // further behind has not been touched since before the archived crop was added, so it
+            // earned from the crop's first second and has nothing left from older crops.
+            let (reward_debt, amount_owed, pending) = if stake.crop_id[i] == archived_id {
+                (
+                    stake.reward_debt[i],
+                    stake.amount_owed[i],
+                    is_pending(stake, i),
+                )
+            } else {
+                (0, 0, is_staking(stake))
+            };
+
+            if pending {
+                let earned =
+                    accrued_per_stake(stake.weighted_stake, farm.archived_rewards_per_token[i])?
+                        .checked_sub(reward_debt)
+                        .ok_or(FarmError::MathOverflow)?
+                        / PRECISION_SCALER;
+
+                stake.archived_owed[i] = u64::try_from(earned)
+                    .ok()
+                    .and_then(|earned| earned.checked_add(amount_owed))
+                    .and_then(|owed| owed.checked_add(stake.archived_owed[i]))
+                    .ok_or(FarmError::MathOverflow)?;
+
+                if stake.archived_owed[i] == 0 {
+                    release_archived(farm, i)?;
+                }
+            }
+        }
+
+        stake.crop_id[i] = farm.crop_id[i];
+        stake.reward_debt[i] = 0;
+        stake.amount_owed[i] = 0;
+        stake.crop_finished[i] = false;
+    }
+
+    Ok(())
+}
+
+/// Moves the crop in slot `index` to the slot's archive if any staker still has rewards coming from
+/// it, and returns how many do. The caller clears the slot afterwards. The farm must already be
+/// accrued to the current time.
+pub fn archive_crop(farm: &mut FarmRewards, index: usize) -> Result<u64, FarmError> {
+    let pending = farm
+        .stakers
+        .checked_sub(farm.crop_stakers_finished[index])
+        .and_then(|unfinished| unfinished.checked_add(farm.crop_stakers_owed[index]))
+        .ok_or(FarmError::MathOverflow)?;
+
+    if pending > 0 {
+        farm.archived_crop_id[index] = farm.crop_id[index];
+        farm.archived_rewards_per_token[index] = farm.crop_rewards_per_token[index];
+        farm.archived_stakers_pending[index] = pending;
+    }
+
+    Ok(pending)
+}
+
+/// Takes the stake's archived rewards for slot `index`, releasing it from the archive. The stake
+/// must already be synced.
+pub fn claim_archived(
+    farm: &mut FarmRewards,
+    stake: &mut StakeRewards,
+    index: usize,
+) -> Result<u64, FarmError> {
+    let claimed = stake.archived_owed[index];
+
+    if claimed > 0 {
+        stake.archived_owed[index] = 0;
+        release_archived(farm, index)?;
+    }
+
+    Ok(claimed)
+}
+
+/// Marks every crop that has emitted all of its rewards and from which the stake has nothing
+/// left to claim. The stake must already be settled.
+pub fn mark_finished_crops(farm: &FarmRewards, stake: &mut StakeRewards) {
+    for i in 0..CROP_COUNT {
+        if farm.crop_created_at[i] == 0 {
+            continue;
+        }
+
+        if farm.crop_last_updated_at[i] >= farm.crop_end_date[i] && stake.amount_owed[i] == 0 {
+            stake.crop_finished[i] = true;
+        }
+    }
+}
+
+/// Moves the stake's contribution to the farm's staker counters from `before` to `after`.
+///
+/// A stake counts towards `stakers` while it has tokens staked. For each crop it then counts
+/// towards `crop_stakers_finished` once it has finished that crop, and a stake that has left the
+/// farm while still owed rewards counts towards `crop_stakers_owed` until it claims them.
+pub fn update_staker_counts(
+    farm: &mut FarmRewards,
+    before: &StakeRewards,
+    after: &StakeRewards,
+) -> Result<(), FarmError> {
+    farm.stakers = move_count(farm.stakers, is_staking(before), is_staking(after))?;
+
+    for i in 0..CROP_COUNT {
+        if farm.crop_created_at[i] == 0 {
+            continue;
+        }
+
+        farm.crop_stakers_finished[i] = move_count(
+            farm.crop_stakers_finished[i],
+            is_staking(before) && before.crop_finished[i],
+            is_staking(after) && after.crop_finished[i],
+        )?;
+
+        farm.crop_stakers_owed[i] = move_count(
+            farm.crop_stakers_owed[i],
+            !is_staking(before) && before.amount_owed[i] > 0,
+            !is_staking(after) && after.amount_owed[i] > 0,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Takes the stake's whole balance out of the farm and drops whatever it is owed from the active
+/// crops, returning the amount to give back. Rewards already moved to `archived_owed` stay
+/// claimable. The farm must already be accrued to the current time.
+pub fn forfeit_stake(farm: &mut FarmRewards, stake: &mut StakeRewards) -> Result<u64, FarmError> {
+    sync_crops(farm, stake)?;
+
+    let before = stake.clone();
+    let amount = stake.amount_staked;
+
+    farm.total_staked_amount = farm
+        .total_staked_amount
+        .checked_sub(amount)
+        .ok_or(FarmError::MathOverflow)?;
+
+    farm.total_weighted_stake = farm
+        .total_weighted_stake
+        .checked_sub(stake.weighted_stake)
+        .ok_or(FarmError::MathOverflow)?;
+
+    stake.amount_staked = 0;
+    stake.weighted_stake = 0;
+    stake.reward_debt = [0; CROP_COUNT];
+    stake.amount_owed = [0; CROP_COUNT];
+
+    update_staker_counts(farm, &before, stake)?;
+
+    Ok(amount)
+}
+
+/// Moves the part of each tranche that has vested by `now` into `vested_amount`. A tranche that
+/// has fully vested frees its slot for the next payout.
+pub fn vest(schedule: &mut VestingSchedule, now: u64) -> Result<(), FarmError> {
+    for i in 0..VESTING_TRANCHES {
+        if schedule.vesting_amount[i] == 0 || now <= schedule.vested_until[i] {
+            continue;
+        }
+
+        let vested = if now >= schedule.vesting_until[i] {
+            schedule.vesting_amount[i]
+        } else {
+            let elapsed = u128::from(now - schedule.vested_until[i]);
+            let remaining = u128::from(schedule.vesting_until[i] - schedule.vested_until[i]);
+
+            u128::from(schedule.vesting_amount[i])
+                .checked_mul(elapsed)
+                .and_then(|vested| vested.checked_div(remaining))
+                .and_then(|vested| u64::try_from(vested).ok())
+                .ok_or(FarmError::MathOverflow)?
+        };
+
+        schedule.vesting_amount[i] = schedule.vesting_amount[i]
+            .checked_sub(vested)
+            .ok_or(FarmError::MathOverflow)?;
+        schedule.vested_amount = schedule
+            .vested_amount
+            .checked_add(vested)
+            .ok_or(FarmError::MathOverflow)?;
+        schedule.vested_until[i] = now;
+
+        if schedule.vesting_amount[i] == 0 {
+            schedule.vested_until[i] = 0;
+            schedule.vesting_until[i] = 0;
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts `amount` vesting over `period` seconds from `now` in a tranche of its own, so it neither
+/// speeds up nor holds back what is already vesting. Fails once every tranche is still vesting;
+/// the rewards then stay owed until one of them has fully vested.
+pub fn add_vesting(
+    schedule: &mut VestingSchedule,
+    amount: u64,
+    period: u64,
+    now: u64,
+) -> Result<(), FarmError> {
+    vest(schedule, now)?;
+
+    let slot = schedule
+        .vesting_amount
+        .iter()
+        .position(|vesting| *vesting == 0)
+        .ok_or(FarmError::VestingFull)?;
+
+    schedule.vesting_amount[slot] = amount;
+    schedule.vested_until[slot] = now;
+    schedule.vesting_until[slot] = now.checked_add(period).ok_or(FarmError::MathOverflow)?;
+
+    Ok(())
+}
+
+fn is_staking(stake: &StakeRewards) -> bool {
+    stake.amount_staked > 0
+}
+
+/// Whether the stake is one of the stakers `archive_crop` counts as still having rewards coming
+/// from the crop in slot `index`.
+fn is_pending(stake: &StakeRewards, index: usize) -> bool {
+    !stake.crop_finished[index] && (is_staking(stake) || stake.amount_owed[index] > 0)
+}
+
+fn release_archived(farm: &mut FarmRewards, index: usize) -> Result<(), FarmError> {
+    farm.archived_stakers_pending[index] = farm.archived_stakers_pending[index]
+        .checked_sub(1)
+        .ok_or(FarmError::MathOverflow)?;
+
+    Ok(())
+}
+
+fn move_count(count: u64, before: bool, after: bool) -> Result<u64, FarmError> {
+    count
+        .checked_sub(u64::from(before))
+        .and_then(|count| count.checked_add(u64::from(after)))
+        .ok_or(FarmError::MathOverflow)
+}
+
+fn accrued_per_stake(weighted_stake: u64, rewards_per_token: u128) -> Result<u128, FarmError> {
+    u128::from(weighted_stake)
+        .checked_mul(rewards_per_token)
+        .ok_or(FarmError::MathOverflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn farm_with_crop(index: usize, rewards_per_second: u64, start: u64, end: u64) -> FarmRewards {
+        let mut farm = FarmRewards::default();
+        farm.crop_rewards_per_second[index] = rewards_per_second;
+        farm.crop_created_at[index] = start;
+        farm.crop_last_updated_at[index] = start;
+        farm.crop_end_date[index] = end;
+        farm
+    }
+
+    fn stake(farm: &mut FarmRewards, stake: &mut StakeRewards, amount: u64) {
+        sync_crops(farm, stake).unwrap();
+        settle_stake(farm, stake).unwrap();
+        let before = stake.clone();
+        farm.total_staked_amount += amount;
+        stake.amount_staked += amount;
+        update_weight(farm, stake, 0).unwrap();
+        update_staker_counts(farm, &before, stake).unwrap();
+        update_reward_debt(farm, stake).unwrap();
+    }
+
+    #[test]
+    fn single_staker_earns_full_emission() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 1_000);
+
+        accrue_farm(&mut farm, 1_020).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+
+        assert_eq!(alice.amount_owed[0], 1_000);
+        assert_eq!(farm.crop_last_updated_at[0], 1_010);
+    }
+
+    #[test]
+    fn equal_stakers_split_rewards() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        let mut bob = StakeRewards::default();
+        stake(&mut farm, &mut alice, 100_000);
+        stake(&mut farm, &mut bob, 100_000);
+
+        accrue_farm(&mut farm, 1_010).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        settle_stake(&farm, &mut bob).unwrap();
+
+        assert_eq!(alice.amount_owed[0], bob.amount_owed[0]);
+        assert!(alice.amount_owed[0] <= 500 && alice.amount_owed[0] >= 499);
+    }
+
+    #[test]
+    fn crops_with_different_end_dates_accrue_independently() {
+        let mut farm = farm_with_crop(0, 10, 1_000, 1_005);
+        farm.crop_rewards_per_second[1] = 10;
+        farm.crop_created_at[1] = 1_000;
+        farm.crop_last_updated_at[1] = 1_000;
+        farm.crop_end_date[1] = 1_020;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 1_010).unwrap();
+        accrue_farm(&mut farm, 1_020).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+
+        assert_eq!(alice.amount_owed[0], 50);
+        assert_eq!(alice.amount_owed[1], 200);
+    }
+
+    #[test]
+    fn time_without_stakers_is_not_paid_out() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+
+        accrue_farm(&mut farm, 1_005).unwrap();
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 100);
+        accrue_farm(&mut farm, 1_010).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+
+        assert_eq!(alice.amount_owed[0], 500);
+    }
+
+    #[test]
+    fn inactive_crops_are_ignored() {
+        let mut farm = FarmRewards {
+            total_staked_amount: 100,
+            total_weighted_stake: 100,
+            ..FarmRewards::default()
+        };
+        farm.crop_rewards_per_second[3] = 100;
+        farm.crop_end_date[3] = 2_000;
+
+        accrue_farm(&mut farm, 1_500).unwrap();
+
+        assert_eq!(farm.crop_rewards_per_token[3], 0);
+        assert_eq!(farm.crop_last_updated_at[3], 0);
+    }
+
+    #[test]
+    fn largest_emission_over_one_second_fits() {
+        let mut farm = farm_with_crop(0, u64::MAX, 1_000, u64::MAX);
+        farm.total_weighted_stake = 1;
+
+        accrue_farm(&mut farm, 1_001).unwrap();
+
+        assert_eq!(
+            farm.crop_rewards_per_token[0],
+            u128::from(u64::MAX) * PRECISION_SCALER
+        );
+    }
+
+    #[test]
+    fn emission_beyond_u128_is_an_overflow() {
+        let mut farm = farm_with_crop(0, u64::MAX, 1_000, u64::MAX);
+        farm.total_weighted_stake = 1;
+
+        let result = accrue_farm(&mut farm, 1_003);
+
+        assert!(matches!(result, Err(FarmError::MathOverflow)));
+    }
+
+    #[test]
+    fn long_gap_overflows_instead_of_wrapping() {
+        let mut farm = farm_with_crop(0, u64::MAX / 2, 0, u64::MAX);
+        farm.crop_created_at[0] = 1;
+        farm.total_weighted_stake = u64::MAX;
+
+        let result = accrue_farm(&mut farm, u64::MAX);
+
+        assert!(matches!(result, Err(FarmError::MathOverflow)));
+    }
+
+    #[test]
+    fn rewards_per_token_overflow_is_reported() {
+        let mut farm = farm_with_crop(0, 1, 1_000, 2_000);
+        farm.total_weighted_stake = 1;
+        farm.crop_rewards_per_token[0] = u128::MAX;
+
+        let result = accrue_farm(&mut farm, 1_001);
+
+        assert!(matches!(result, Err(FarmError::MathOverflow)));
+    }
+
+    #[test]
+    fn settling_more_than_u64_is_an_overflow() {
+        let mut farm = farm_with_crop(0, u64::MAX, 1_000, u64::MAX);
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 1);
+        accrue_farm(&mut farm, 1_001).unwrap();
+        alice.amount_owed[0] = 1;
+
+        let result = settle_stake(&farm, &mut alice);
+
+        assert!(matches!(result, Err(FarmError::MathOverflow)));
+    }
+
+    #[test]
+    fn largest_stake_against_large_index_is_an_overflow() {
+        let mut farm = farm_with_crop(0, 1, 1_000, 2_000);
+        farm.crop_rewards_per_token[0] = u128::from(u64::MAX) * 2;
+        let mut alice = StakeRewards {
+            amount_staked: u64::MAX,
+            weighted_stake: u64::MAX,
+            ..StakeRewards::default()
+        };
+
+        assert!(matches!(
+            update_reward_debt(&farm, &mut alice),
+            Err(FarmError::MathOverflow)
+        ));
+        assert!(matches!(
+            settle_stake(&farm, &mut alice),
+            Err(FarmError::MathOverflow)
+        ));
+    }
+
+    fn finish_crop(farm: &mut FarmRewards, stake: &mut StakeRewards, now: u64) -> u64 {
+        accrue_farm(farm, now).unwrap();
+        sync_crops(farm, stake).unwrap();
+        settle_stake(farm, stake).unwrap();
+        let before = stake.clone();
+        let claimed = stake.amount_owed[0];
+        stake.amount_owed[0] = 0;
+        mark_finished_crops(farm, stake);
+        update_staker_counts(farm, &before, stake).unwrap();
+        update_reward_debt(farm, stake).unwrap();
+        claimed
+    }
+
+    #[test]
+    fn staker_counts_follow_amount_staked() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+        assert_eq!(farm.stakers, 1);
+
+        let before = alice.clone();
+        alice.amount_staked = 0;
+        farm.total_staked_amount = 0;
+        update_staker_counts(&mut farm, &before, &alice).unwrap();
+        assert_eq!(farm.stakers, 0);
+    }
+
+    #[test]
+    fn crop_is_finished_only_after_claiming_past_the_end() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        assert_eq!(finish_crop(&mut farm, &mut alice, 1_005), 500);
+        assert_eq!(farm.crop_stakers_finished[0], 0);
+
+        assert_eq!(finish_crop(&mut farm, &mut alice, 1_011), 500);
+        assert_eq!(farm.crop_stakers_finished[0], 1);
+        assert_eq!(farm.stakers, farm.crop_stakers_finished[0]);
+    }
+
+    #[test]
+    fn leaving_with_unclaimed_rewards_is_tracked() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 1_005).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        let before = alice.clone();
+        alice.amount_staked = 0;
+        farm.total_staked_amount = 0;
+        update_staker_counts(&mut farm, &before, &alice).unwrap();
+
+        assert_eq!(farm.stakers, 0);
+        assert_eq!(farm.crop_stakers_owed[0], 1);
+
+        assert_eq!(finish_crop(&mut farm, &mut alice, 1_020), 500);
+        assert_eq!(farm.crop_stakers_owed[0], 0);
+    }
+
+    #[test]
+    fn forfeiting_leaves_other_stakers_untouched() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        let mut bob = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+        stake(&mut farm, &mut bob, 10);
+
+        accrue_farm(&mut farm, 1_004).unwrap();
+        assert_eq!(forfeit_stake(&mut farm, &mut alice).unwrap(), 10);
+        assert_eq!(alice, StakeRewards::default());
+        assert_eq!(farm.total_staked_amount, 10);
+        assert_eq!(farm.total_weighted_stake, 10);
+        assert_eq!(farm.stakers, 1);
+
+        assert_eq!(finish_crop(&mut farm, &mut bob, 1_010), 800);
+        assert_eq!(farm.crop_stakers_finished[0], farm.stakers);
+        assert_eq!(farm.crop_stakers_owed[0], 0);
+    }
+
+    #[test]
+    fn reused_slot_starts_from_a_clean_debt() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_id[0] = 1;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+        finish_crop(&mut farm, &mut alice, 1_010);
+
+        replace_crop(&mut farm, 2, 1_020, 1_030);
+        accrue_farm(&mut farm, 1_030).unwrap();
+        sync_crops(&mut farm, &mut alice).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+
+        assert_eq!(alice.crop_id[0], 2);
+        assert!(!alice.crop_finished[0]);
+        assert_eq!(alice.amount_owed[0], 1_000);
+    }
+
+    fn replace_crop(farm: &mut FarmRewards, id: u64, start: u64, end: u64) {
+        farm.crop_id[0] = id;
+        farm.crop_rewards_per_token[0] = 0;
+        farm.crop_created_at[0] = start;
+        farm.crop_last_updated_at[0] = start;
+        farm.crop_end_date[0] = end;
+        farm.crop_allocated[0] = 0;
+        farm.crop_stakers_finished[0] = 0;
+        farm.crop_stakers_owed[0] = 0;
+    }
+
+    #[test]
+    fn topping_up_extends_the_crop() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_funded[0] = 1_000;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 1_005).unwrap();
+        farm.crop_funded[0] += 1_000;
+        reschedule_crop(&mut farm, 0, 1_005, 100).unwrap();
+        assert_eq!(farm.crop_end_date[0], 1_020);
+
+        accrue_farm(&mut farm, 1_030).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        assert_eq!(alice.amount_owed[0], 2_000);
+        assert_eq!(farm.crop_allocated[0], farm.crop_funded[0]);
+    }
+
+    #[test]
+    fn raising_the_rate_ends_the_crop_sooner() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_funded[0] = 1_000;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 1_002).unwrap();
+        reschedule_crop(&mut farm, 0, 1_002, 200).unwrap();
+        assert_eq!(farm.crop_end_date[0], 1_006);
+
+        accrue_farm(&mut farm, 1_010).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        assert_eq!(alice.amount_owed[0], 1_000);
+    }
+
+    #[test]
+    fn rescheduling_rejects_a_zero_rate() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_funded[0] = 1_000;
+
+        assert!(matches!(
+            reschedule_crop(&mut farm, 0, 1_000, 0),
+            Err(FarmError::ZeroRewardRate)
+        ));
+    }
+
+    #[test]
+    fn scheduled_crop_waits_for_its_start() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_start_at[0] = 1_000;
+        farm.crop_funded[0] = 1_000;
+        let mut alice = StakeRewards::default();
+        accrue_farm(&mut farm, 990).unwrap();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 995).unwrap();
+        assert_eq!(farm.crop_rewards_per_token[0], 0);
+        assert_eq!(farm.crop_last_updated_at[0], 1_000);
+
+        reschedule_crop(&mut farm, 0, 995, 50).unwrap();
+        assert_eq!(farm.crop_end_date[0], 1_020);
+
+        accrue_farm(&mut farm, 1_020).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        assert_eq!(alice.amount_owed[0], 1_000);
+    }
+
+    #[test]
+    fn allocation_only_counts_time_with_stake() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        accrue_farm(&mut farm, 1_002).unwrap();
+        stake(&mut farm, &mut alice, 10);
+
+        accrue_farm(&mut farm, 1_020).unwrap();
+
+        assert_eq!(farm.crop_allocated[0], 800);
+    }
+
+    #[test]
+    fn removed_crop_stays_claimable_after_slot_reuse() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_id[0] = 1;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+        accrue_farm(&mut farm, 1_010).unwrap();
+
+        assert_eq!(archive_crop(&mut farm, 0).unwrap(), 1);
+        replace_crop(&mut farm, 2, 1_020, 1_030);
+
+        sync_crops(&mut farm, &mut alice).unwrap();
+        assert_eq!(alice.archived_owed[0], 1_000);
+        assert_eq!(farm.archived_stakers_pending[0], 1);
+
+        assert_eq!(claim_archived(&mut farm, &mut alice, 0).unwrap(), 1_000);
+        assert_eq!(farm.archived_stakers_pending[0], 0);
+    }
+
+    #[test]
+    fn untouched_stake_earns_the_whole_archived_crop() {
+        let mut farm = FarmRewards::default();
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+
+        replace_crop(&mut farm, 1, 1_000, 1_010);
+        farm.crop_rewards_per_second[0] = 100;
+        accrue_farm(&mut farm, 1_010).unwrap();
+
+        assert_eq!(archive_crop(&mut farm, 0).unwrap(), 1);
+        replace_crop(&mut farm, 2, 1_020, 1_030);
+
+        sync_crops(&mut farm, &mut alice).unwrap();
+        assert_eq!(alice.archived_owed[0], 1_000);
+    }
+
+    #[test]
+    fn finished_stakers_are_not_archived() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        farm.crop_id[0] = 1;
+        let mut alice = StakeRewards::default();
+        stake(&mut farm, &mut alice, 10);
+        finish_crop(&mut farm, &mut alice, 1_011);
+
+        assert_eq!(archive_crop(&mut farm, 0).unwrap(), 0);
+        assert_eq!(farm.archived_crop_id[0], 0);
+    }
+
+    #[test]
+    fn locked_stake_earns_its_weight() {
+        let mut farm = farm_with_crop(0, 100, 1_000, 1_010);
+        let mut alice = StakeRewards::default();
+        let mut bob = StakeRewards::default();
+        lock_stake(&mut bob, 3, 1_000).unwrap();
+        stake(&mut farm, &mut alice, 10);
+        stake(&mut farm, &mut bob, 10);
+        assert_eq!(bob.weighted_stake, 20);
+        assert_eq!(farm.total_weighted_stake, 30);
+
+        accrue_farm(&mut farm, 1_010).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+        settle_stake(&farm, &mut bob).unwrap();
+
+        assert_eq!(bob.amount_owed[0], 2 * alice.amount_owed[0]);
+        assert!(alice.amount_owed[0] + bob.amount_owed[0] <= 1_000);
+    }
+
+    #[test]
+    fn expired_lock_drops_back_to_the_base_weight() {
+        let mut farm = FarmRewards::default();
+        let mut alice = StakeRewards::default();
+        lock_stake(&mut alice, 1, 1_000).unwrap();
+        alice.amount_staked = 100;
+        update_weight(&mut farm, &mut alice, 1_000).unwrap();
+        assert_eq!(farm.total_weighted_stake, 125);
+
+        update_weight(&mut farm, &mut alice, 1_000 + LOCK_TIERS[1].0).unwrap();
+
+        assert_eq!(alice.lock_tier, 0);
+        assert_eq!(alice.weighted_stake, 100);
+        assert_eq!(farm.total_weighted_stake, 100);
+    }
+
+    #[test]
+    fn locked_stake_cannot_switch_to_a_shorter_lock() {
+        let mut alice = StakeRewards::default();
+        lock_stake(&mut alice, 2, 1_000).unwrap();
+
+        assert!(matches!(
+            lock_stake(&mut alice, 1, 1_001),
+            Err(FarmError::LockTooShort)
+        ));
+        assert!(matches!(
+            lock_stake(&mut alice, 4, 1_001),
+            Err(FarmError::InvalidLockTier)
+        ));
+
+        lock_stake(&mut alice, 1, 1_000 + LOCK_TIERS[2].0).unwrap();
+        assert_eq!(alice.lock_tier, 1);
+    }
+
+    #[test]
+    fn rewards_vest_linearly() {
+        let mut schedule = VestingSchedule::default();
+        add_vesting(&mut schedule, 1_000, 100, 1_000).unwrap();
+
+        vest(&mut schedule, 1_025).unwrap();
+        assert_eq!(schedule.vested_amount, 250);
+        assert_eq!(schedule.vesting_amount[0], 750);
+
+        vest(&mut schedule, 1_100).unwrap();
+        assert_eq!(schedule.vested_amount, 1_000);
+        assert_eq!(schedule.vesting_amount[0], 0);
+
+        vest(&mut schedule, 2_000).unwrap();
+        assert_eq!(schedule.vested_amount, 1_000);
+    }
+
+    #[test]
+    fn each_payout_vests_on_its_own_schedule() {
+        let mut schedule = VestingSchedule::default();
+        add_vesting(&mut schedule, 1_000, 100, 1_000).unwrap();
+        add_vesting(&mut schedule, 500, 100, 1_050).unwrap();
+
+        assert_eq!(schedule.vested_amount, 500);
+        assert_eq!(schedule.vesting_amount, [500, 500, 0, 0]);
+        assert_eq!(schedule.vesting_until, [1_100, 1_150, 0, 0]);
+
+        // The first payout still ends on time, and the second one is not sped up
+        vest(&mut schedule, 1_100).unwrap();
+        assert_eq!(schedule.vested_amount, 1_250);
+        assert_eq!(schedule.vesting_amount, [0, 250, 0, 0]);
+
+        vest(&mut schedule, 1_150).unwrap();
+        assert_eq!(schedule.vested_amount, 1_500);
+
+        // A tiny payout over a long period leaves a large one ending when it did
+        add_vesting(&mut schedule, 1_000_000, 100, 2_000).unwrap();
+        add_vesting(&mut schedule, 1, 1_000_000, 2_000).unwrap();
+        assert_eq!(schedule.vesting_until, [2_100, 1_002_000, 0, 0]);
+
+        vest(&mut schedule, 2_100).unwrap();
+        assert_eq!(schedule.vested_amount, 1_001_500);
+        assert_eq!(schedule.vesting_amount, [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn vesting_waits_for_a_free_tranche() {
+        let mut schedule = VestingSchedule::default();
+        for i in 0..VESTING_TRANCHES as u64 {
+            add_vesting(&mut schedule, 100, 10 + i, 1_000).unwrap();
+        }
+
+        let result = add_vesting(&mut schedule, 100, 10, 1_005);
+        assert!(matches!(result, Err(FarmError::VestingFull)));
+
+        add_vesting(&mut schedule, 100, 10, 1_010).unwrap();
+        assert_eq!(schedule.vesting_until[0], 1_020);
+    }
+
+    #[test]
+    fn boost_ends_when_the_lock_does_without_interaction() {
+        let end = 1_000 + 40 * DAY;
+        let mut farm = farm_with_crop(0, 10, 1_000, end);
+        let mut alice = StakeRewards::default();
+        let mut bob = StakeRewards::default();
+        lock_stake(&mut alice, 1, 1_000).unwrap();
+        stake(&mut farm, &mut alice, 100);
+        stake(&mut farm, &mut bob, 125);
+        alice.last_updated_at = 1_000;
+
+        accrue_farm(&mut farm, end).unwrap();
+        settle_expired_lock(&mut farm, &mut alice, end).unwrap();
+        settle_stake(&farm, &mut alice).unwrap();
+
+        // Half of the emission while the lock lasted, then 100 of the 250 weight
+        let emitted = 10 * 40 * DAY;
+        let expected = emitted / 40 * 30 / 2 + emitted / 40 * 10 * 100 / 250;
+        assert!(alice.amount_owed[0].abs_diff(expected) <= 1);
+
+        // The boost past the lock goes back to the crop rather than staying allocated
+        let forfeited = emitted / 2 - expected;
+        assert!(farm.crop_allocated[0].abs_diff(emitted - forfeited) <= 1);
+    }
+
+    #[test]
+    fn expired_lock_settlement_reports_overflow() {
+        let mut farm = farm_with_crop(0, 10, 1_000, 2_000);
+        let mut alice = StakeRewards::default();
+        lock_stake(&mut alice, 1, 1_000).unwrap();
+        stake(&mut farm, &mut alice, u64::MAX / 2);
+        alice.last_updated_at = 1_000;
+        alice.lock_until = 1_500;
+        farm.crop_rewards_per_token[0] = u128::MAX / u128::from(u64::MAX);
+
+        let result = settle_expired_lock(&mut farm, &mut alice, 2_000);
+        assert!(matches!(result, Err(FarmError::MathOverflow)));
+    }
+}
@@ -1,8 +1,13 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(unused_mut)]
-use crate::{id, seahorse_util::*};
-use anchor_lang::{prelude::*, solana_program};
+use crate::{
+    accrual::{self, FarmRewards, StakeRewards, VestingSchedule},
+    error::FarmError,
+    id,
+    seahorse_util::*,
+};
+use anchor_lang::{prelude::*, solana_program, Discriminator};
 use anchor_spl::token::{self, Mint, Token, TokenAccount};
 use std::{cell::RefCell, rc::Rc};
 
@@ -22,6 +27,33 @@ pub struct Farm {
     pub crop_end_date: [u64; 8],
     pub crop_created_at: [u64; 8],
     pub crop_stakers_finished: [u64; 8],
+    pub crop_last_updated_at: [u64; 8],
+    pub crop_nonce: u64,
+    pub crop_id: [u64; 8],
+    pub crop_stakers_owed: [u64; 8],
+    pub crop_funded: [u64; 8],
+    pub crop_allocated: [u64; 8],
+    pub crop_claimed: [u64; 8],
+    pub archived_crop_id: [u64; 8],
+    pub archived_crop_vault: [Pubkey; 8],
+    pub archived_rewards_per_token: [u128; 8],
+    pub archived_stakers_pending: [u64; 8],
+    pub crop_start_at: [u64; 8],
+    pub pending_owner: Pubkey,
+    pub paused: bool,
+    pub authority_bump: u8,
+    pub crop_fees: [u64; 8],
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+    pub fee_vault: Pubkey,
+    pub total_weighted_stake: u64,
+    pub unbonding_period: u64,
+    pub total_unbonding: u64,
+    pub crop_vesting_period: [u64; 8],
+    pub active_vestings: u64,
+    pub crop_vaults: u64,
+    pub archived_crop_fees: [u64; 8],
+    pub archived_vesting_period: [u64; 8],
 }
 
 impl<'info, 'entrypoint> Farm {
@@ -42,6 +74,33 @@ impl<'info, 'entrypoint> Farm {
         let crop_end_date = Mutable::new(account.crop_end_date.clone());
         let crop_created_at = Mutable::new(account.crop_created_at.clone());
         let crop_stakers_finished = Mutable::new(account.crop_stakers_finished.clone());
+        let crop_last_updated_at = Mutable::new(account.crop_last_updated_at.clone());
+        let crop_nonce = account.crop_nonce;
+        let crop_id = Mutable::new(account.crop_id.clone());
+        let crop_stakers_owed = Mutable::new(account.crop_stakers_owed.clone());
+        let crop_funded = Mutable::new(account.crop_funded.clone());
+        let crop_allocated = Mutable::new(account.crop_allocated.clone());
+        let crop_claimed = Mutable::new(account.crop_claimed.clone());
+        let archived_crop_id = Mutable::new(account.archived_crop_id.clone());
+        let archived_crop_vault = Mutable::new(account.archived_crop_vault.clone());
+        let archived_rewards_per_token = Mutable::new(account.archived_rewards_per_token.clone());
+        let archived_stakers_pending = Mutable::new(account.archived_stakers_pending.clone());
+        let crop_start_at = Mutable::new(account.crop_start_at.clone());
+        let pending_owner = account.pending_owner.clone();
+        let paused = account.paused;
+        let authority_bump = account.authority_bump;
+        let crop_fees = Mutable::new(account.crop_fees.clone());
+        let deposit_fee_bps = account.deposit_fee_bps;
+        let withdraw_fee_bps = account.withdraw_fee_bps;
+        let fee_vault = account.fee_vault.clone();
+        let total_weighted_stake = account.total_weighted_stake;
+        let unbonding_period = account.unbonding_period;
+        let total_unbonding = account.total_unbonding;
+        let crop_vesting_period = Mutable::new(account.crop_vesting_period.clone());
+        let active_vestings = account.active_vestings;
+        let crop_vaults = account.crop_vaults;
+        let archived_crop_fees = Mutable::new(account.archived_crop_fees.clone());
+        let archived_vesting_period = Mutable::new(account.archived_vesting_period.clone());
 
         Mutable::new(LoadedFarm {
             __account__: account,
@@ -59,6 +118,33 @@ impl<'info, 'entrypoint> Farm {
             crop_end_date,
             crop_created_at,
             crop_stakers_finished,
+            crop_last_updated_at,
+            crop_nonce,
+            crop_id,
+            crop_stakers_owed,
+            crop_funded,
+            crop_allocated,
+            crop_claimed,
+            archived_crop_id,
+            archived_crop_vault,
+            archived_rewards_per_token,
+            archived_stakers_pending,
+            crop_start_at,
+            pending_owner,
+            paused,
+            authority_bump,
+            crop_fees,
+            deposit_fee_bps,
+            withdraw_fee_bps,
+            fee_vault,
+            total_weighted_stake,
+            unbonding_period,
+            total_unbonding,
+            crop_vesting_period,
+            active_vestings,
+            crop_vaults,
+            archived_crop_fees,
+            archived_vesting_period,
         })
     }
 
@@ -115,6 +201,114 @@ impl<'info, 'entrypoint> Farm {
         let crop_stakers_finished = loaded.crop_stakers_finished.borrow().clone();
 
         loaded.__account__.crop_stakers_finished = crop_stakers_finished;
+
+        let crop_last_updated_at = loaded.crop_last_updated_at.borrow().clone();
+
+        loaded.__account__.crop_last_updated_at = crop_last_updated_at;
+
+        let crop_nonce = loaded.crop_nonce;
+
+        loaded.__account__.crop_nonce = crop_nonce;
+
+        let crop_id = loaded.crop_id.borrow().clone();
+
+        loaded.__account__.crop_id = crop_id;
+
+        let crop_stakers_owed = loaded.crop_stakers_owed.borrow().clone();
+
+        loaded.__account__.crop_stakers_owed = crop_stakers_owed;
+
+        let crop_funded = loaded.crop_funded.borrow().clone();
+
+        loaded.__account__.crop_funded = crop_funded;
+
+        let crop_allocated = loaded.crop_allocated.borrow().clone();
+
+        loaded.__account__.crop_allocated = crop_allocated;
+
+        let crop_claimed = loaded.crop_claimed.borrow().clone();
+
+        loaded.__account__.crop_claimed = crop_claimed;
+
+        let archived_crop_id = loaded.archived_crop_id.borrow().clone();
+
+        loaded.__account__.archived_crop_id = archived_crop_id;
+
+        let archived_crop_vault = loaded.archived_crop_vault.borrow().clone();
+
+        loaded.__account__.archived_crop_vault = archived_crop_vault;
+
+        let archived_rewards_per_token = loaded.archived_rewards_per_token.borrow().clone();
+
+        loaded.__account__.archived_rewards_per_token = archived_rewards_per_token;
+
+        let archived_stakers_pending = loaded.archived_stakers_pending.borrow().clone();
+
+        loaded.__account__.archived_stakers_pending = archived_stakers_pending;
+
+        let crop_start_at = loaded.crop_start_at.borrow().clone();
+
+        loaded.__account__.crop_start_at = crop_start_at;
+
+        let pending_owner = loaded.pending_owner.clone();
+
+        loaded.__account__.pending_owner = pending_owner;
+
+        let paused = loaded.paused;
+
+        loaded.__account__.paused = paused;
+
+        let authority_bump = loaded.authority_bump;
+
+        loaded.__account__.authority_bump = authority_bump;
+
+        let crop_fees = loaded.crop_fees.borrow().clone();
+
+        loaded.__account__.crop_fees = crop_fees;
+
+        let deposit_fee_bps = loaded.deposit_fee_bps;
+
+        loaded.__account__.deposit_fee_bps = deposit_fee_bps;
+
+        let withdraw_fee_bps = loaded.withdraw_fee_bps;
+
+        loaded.__account__.withdraw_fee_bps = withdraw_fee_bps;
+
+        let fee_vault = loaded.fee_vault.clone();
+
+        loaded.__account__.fee_vault = fee_vault;
+
+        let total_weighted_stake = loaded.total_weighted_stake;
+
+        loaded.__account__.total_weighted_stake = total_weighted_stake;
+
+        let unbonding_period = loaded.unbonding_period;
+
+        loaded.__account__.unbonding_period = unbonding_period;
+
+        let total_unbonding = loaded.total_unbonding;
+
+        loaded.__account__.total_unbonding = total_unbonding;
+
+        let crop_vesting_period = loaded.crop_vesting_period.borrow().clone();
+
+        loaded.__account__.crop_vesting_period = crop_vesting_period;
+
+        let active_vestings = loaded.active_vestings;
+
+        loaded.__account__.active_vestings = active_vestings;
+
+        let crop_vaults = loaded.crop_vaults;
+
+        loaded.__account__.crop_vaults = crop_vaults;
+
+        let archived_crop_fees = loaded.archived_crop_fees.borrow().clone();
+
+        loaded.__account__.archived_crop_fees = archived_crop_fees;
+
+        let archived_vesting_period = loaded.archived_vesting_period.borrow().clone();
+
+        loaded.__account__.archived_vesting_period = archived_vesting_period;
     }
 }
 
@@ -135,12 +329,88 @@ pub struct LoadedFarm<'info, 'entrypoint> {
     pub crop_end_date: Mutable<[u64; 8]>,
     pub crop_created_at: Mutable<[u64; 8]>,
     pub crop_stakers_finished: Mutable<[u64; 8]>,
+    pub crop_last_updated_at: Mutable<[u64; 8]>,
+    pub crop_nonce: u64,
+    pub crop_id: Mutable<[u64; 8]>,
+    pub crop_stakers_owed: Mutable<[u64; 8]>,
+    pub crop_funded: Mutable<[u64; 8]>,
+    pub crop_allocated: Mutable<[u64; 8]>,
+    pub crop_claimed: Mutable<[u64; 8]>,
+    pub archived_crop_id: Mutable<[u64; 8]>,
+    pub archived_crop_vault: Mutable<[Pubkey; 8]>,
+    pub archived_rewards_per_token: Mutable<[u128; 8]>,
+    pub archived_stakers_pending: Mutable<[u64; 8]>,
+    pub crop_start_at: Mutable<[u64; 8]>,
+    pub pending_owner: Pubkey,
+    pub paused: bool,
+    pub authority_bump: u8,
+    pub crop_fees: Mutable<[u64; 8]>,
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+    pub fee_vault: Pubkey,
+    pub total_weighted_stake: u64,
+    pub unbonding_period: u64,
+    pub total_unbonding: u64,
+    pub crop_vesting_period: Mutable<[u64; 8]>,
+    pub active_vestings: u64,
+    pub crop_vaults: u64,
+    pub archived_crop_fees: Mutable<[u64; 8]>,
+    pub archived_vesting_period: Mutable<[u64; 8]>,
+}
+
+impl<'info, 'entrypoint> LoadedFarm<'info, 'entrypoint> {
+    pub fn rewards(&self) -> FarmRewards {
+        FarmRewards {
+            total_staked_amount: self.total_staked_amount,
+            total_weighted_stake: self.total_weighted_stake,
+            stakers: self.stakers,
+            crop_id: *self.crop_id.borrow(),
+            crop_rewards_per_second: *self.crop_rewards_per_second.borrow(),
+            crop_rewards_per_token: *self.crop_rewards_per_token.borrow(),
+            crop_end_date: *self.crop_end_date.borrow(),
+            crop_created_at: *self.crop_created_at.borrow(),
+            crop_start_at: *self.crop_start_at.borrow(),
+            crop_last_updated_at: *self.crop_last_updated_at.borrow(),
+            crop_stakers_finished: *self.crop_stakers_finished.borrow(),
+            crop_stakers_owed: *self.crop_stakers_owed.borrow(),
+            crop_funded: *self.crop_funded.borrow(),
+            crop_allocated: *self.crop_allocated.borrow(),
+            archived_crop_id: *self.archived_crop_id.borrow(),
+            archived_rewards_per_token: *self.archived_rewards_per_token.borrow(),
+            archived_stakers_pending: *self.archived_stakers_pending.borrow(),
+        }
+    }
+
+    pub fn set_rewards(&mut self, rewards: &FarmRewards) {
+        self.total_staked_amount = rewards.total_staked_amount;
+        self.total_weighted_stake = rewards.total_weighted_stake;
+        self.stakers = rewards.stakers;
+        *self.crop_id.borrow_mut() = rewards.crop_id;
+        *self.crop_rewards_per_second.borrow_mut() = rewards.crop_rewards_per_second;
+        *self.crop_rewards_per_token.borrow_mut() = rewards.crop_rewards_per_token;
+        *self.crop_end_date.borrow_mut() = rewards.crop_end_date;
+        *self.crop_created_at.borrow_mut() = rewards.crop_created_at;
+        *self.crop_start_at.borrow_mut() = rewards.crop_start_at;
+        *self.crop_last_updated_at.borrow_mut() = rewards.crop_last_updated_at;
+        *self.crop_stakers_finished.borrow_mut() = rewards.crop_stakers_finished;
+        *self.crop_stakers_owed.borrow_mut() = rewards.crop_stakers_owed;
+        *self.crop_funded.borrow_mut() = rewards.crop_funded;
+        *self.crop_allocated.borrow_mut() = rewards.crop_allocated;
+        *self.archived_crop_id.borrow_mut() = rewards.archived_crop_id;
+        *self.archived_rewards_per_token.borrow_mut() = rewards.archived_rewards_per_token;
+        *self.archived_stakers_pending.borrow_mut() = rewards.archived_stakers_pending;
+    }
 }
 
 #[account]
 #[derive(Debug)]
 pub struct Protocol {
     pub bump_seed: u8,
+    pub admin: Pubkey,
+    pub paused: bool,
+    pub fee_bps: u16,
+    pub treasury: Pubkey,
+    pub allowed_stake_mints: [Pubkey; 8],
 }
 
 impl<'info, 'entrypoint> Protocol {
@@ -149,11 +419,21 @@ impl<'info, 'entrypoint> Protocol {
         programs_map: &'entrypoint ProgramsMap<'info>,
     ) -> Mutable<LoadedProtocol<'info, 'entrypoint>> {
         let bump_seed = account.bump_seed;
+        let admin = account.admin.clone();
+        let paused = account.paused;
+        let fee_bps = account.fee_bps;
+        let treasury = account.treasury.clone();
+        let allowed_stake_mints = Mutable::new(account.allowed_stake_mints.clone());
 
         Mutable::new(LoadedProtocol {
             __account__: account,
             __programs__: programs_map,
             bump_seed,
+            admin,
+            paused,
+            fee_bps,
+            treasury,
+            allowed_stake_mints,
         })
     }
 
@@ -162,6 +442,26 @@ impl<'info, 'entrypoint> Protocol {
         let bump_seed = loaded.bump_seed;
 
         loaded.__account__.bump_seed = bump_seed;
+
+        let admin = loaded.admin.clone();
+
+        loaded.__account__.admin = admin;
+
+        let paused = loaded.paused;
+
+        loaded.__account__.paused = paused;
+
+        let fee_bps = loaded.fee_bps;
+
+        loaded.__account__.fee_bps = fee_bps;
+
+        let treasury = loaded.treasury.clone();
+
+        loaded.__account__.treasury = treasury;
+
+        let allowed_stake_mints = loaded.allowed_stake_mints.borrow().clone();
+
+        loaded.__account__.allowed_stake_mints = allowed_stake_mints;
     }
 }
 
@@ -170,6 +470,11 @@ pub struct LoadedProtocol<'info, 'entrypoint> {
     pub __account__: &'entrypoint mut Box<Account<'info, Protocol>>,
     pub __programs__: &'entrypoint ProgramsMap<'info>,
     pub bump_seed: u8,
+    pub admin: Pubkey,
+    pub paused: bool,
+    pub fee_bps: u16,
+    pub treasury: Pubkey,
+    pub allowed_stake_mints: Mutable<[Pubkey; 8]>,
 }
 
 #[account]
@@ -183,6 +488,17 @@ pub struct Stake {
     pub reward_debt: [u128; 8],
     pub last_gathered_at: [u64; 8],
     pub amount_owed: [u64; 8],
+    pub crop_id: [u64; 8],
+    pub crop_finished: [bool; 8],
+    pub archived_owed: [u64; 8],
+    pub reward_recipient: Pubkey,
+    pub weighted_stake: u64,
+    pub lock_tier: u8,
+    pub lock_until: u64,
+    pub unbonding_amount: [u64; 4],
+    pub unbonding_until: [u64; 4],
+    pub active_vestings: u64,
+    pub unbonding_fee: [u64; 4],
 }
 
 impl<'info, 'entrypoint> Stake {
@@ -198,6 +514,17 @@ impl<'info, 'entrypoint> Stake {
         let reward_debt = Mutable::new(account.reward_debt.clone());
         let last_gathered_at = Mutable::new(account.last_gathered_at.clone());
         let amount_owed = Mutable::new(account.amount_owed.clone());
+        let crop_id = Mutable::new(account.crop_id.clone());
+        let crop_finished = Mutable::new(account.crop_finished.clone());
+        let archived_owed = Mutable::new(account.archived_owed.clone());
+        let reward_recipient = account.reward_recipient.clone();
+        let weighted_stake = account.weighted_stake;
+        let lock_tier = account.lock_tier;
+        let lock_until = account.lock_until;
+        let unbonding_amount = Mutable::new(account.unbonding_amount.clone());
+        let unbonding_until = Mutable::new(account.unbonding_until.clone());
+        let active_vestings = account.active_vestings;
+        let unbonding_fee = Mutable::new(account.unbonding_fee.clone());
 
         Mutable::new(LoadedStake {
             __account__: account,
@@ -210,6 +537,17 @@ impl<'info, 'entrypoint> Stake {
             reward_debt,
             last_gathered_at,
             amount_owed,
+            crop_id,
+            crop_finished,
+            archived_owed,
+            reward_recipient,
+            weighted_stake,
+            lock_tier,
+            lock_until,
+            unbonding_amount,
+            unbonding_until,
+            active_vestings,
+            unbonding_fee,
         })
     }
 
@@ -246,6 +584,50 @@ impl<'info, 'entrypoint> Stake {
         let amount_owed = loaded.amount_owed.borrow().clone();
 
         loaded.__account__.amount_owed = amount_owed;
+
+        let crop_id = loaded.crop_id.borrow().clone();
+
+        loaded.__account__.crop_id = crop_id;
+
+        let crop_finished = loaded.crop_finished.borrow().clone();
+
+        loaded.__account__.crop_finished = crop_finished;
+
+        let archived_owed = loaded.archived_owed.borrow().clone();
+
+        loaded.__account__.archived_owed = archived_owed;
+
+        let reward_recipient = loaded.reward_recipient.clone();
+
+        loaded.__account__.reward_recipient = reward_recipient;
+
+        let weighted_stake = loaded.weighted_stake;
+
+        loaded.__account__.weighted_stake = weighted_stake;
+
+        let lock_tier = loaded.lock_tier;
+
+        loaded.__account__.lock_tier = lock_tier;
+
+        let lock_until = loaded.lock_until;
+
+        loaded.__account__.lock_until = lock_until;
+
+        let unbonding_amount = loaded.unbonding_amount.borrow().clone();
+
+        loaded.__account__.unbonding_amount = unbonding_amount;
+
+        let unbonding_until = loaded.unbonding_until.borrow().clone();
+
+        loaded.__account__.unbonding_until = unbonding_until;
+
+        let active_vestings = loaded.active_vestings;
+
+        loaded.__account__.active_vestings = active_vestings;
+
+        let unbonding_fee = loaded.unbonding_fee.borrow().clone();
+
+        loaded.__account__.unbonding_fee = unbonding_fee;
     }
 }
 
@@ -261,98 +643,269 @@ pub struct LoadedStake<'info, 'entrypoint> {
     pub reward_debt: Mutable<[u128; 8]>,
     pub last_gathered_at: Mutable<[u64; 8]>,
     pub amount_owed: Mutable<[u64; 8]>,
+    pub crop_id: Mutable<[u64; 8]>,
+    pub crop_finished: Mutable<[bool; 8]>,
+    pub archived_owed: Mutable<[u64; 8]>,
+    pub reward_recipient: Pubkey,
+    pub weighted_stake: u64,
+    pub lock_tier: u8,
+    pub lock_until: u64,
+    pub unbonding_amount: Mutable<[u64; 4]>,
+    pub unbonding_until: Mutable<[u64; 4]>,
+    pub active_vestings: u64,
+    pub unbonding_fee: Mutable<[u64; 4]>,
 }
 
-pub fn add_crop_handler<'info>(
-    mut crop_index: u8,
-    mut reward_amount: u64,
-    mut rewards_per_second: u64,
-    mut farm: Mutable<LoadedFarm<'info, '_>>,
-    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
-    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
-    mut clock: Sysvar<'info, Clock>,
-    mut signer: SeahorseSigner<'info, '_>,
-) -> () {
-    if !(farm.borrow().owner == signer.key()) {
-        panic!("Wrong signer");
+impl<'info, 'entrypoint> LoadedStake<'info, 'entrypoint> {
+    pub fn rewards(&self) -> StakeRewards {
+        StakeRewards {
+            amount_staked: self.amount_staked,
+            weighted_stake: self.weighted_stake,
+            lock_tier: self.lock_tier,
+            lock_until: self.lock_until,
+            last_updated_at: self.last_updated_at,
+            crop_id: *self.crop_id.borrow(),
+            reward_debt: *self.reward_debt.borrow(),
+            amount_owed: *self.amount_owed.borrow(),
+            crop_finished: *self.crop_finished.borrow(),
+            archived_owed: *self.archived_owed.borrow(),
+        }
     }
 
-    if !(crop_index < <u8 as TryFrom<_>>::try_from(5).unwrap()) {
-        panic!("Index too high");
+    pub fn set_rewards(&mut self, rewards: &StakeRewards) {
+        self.amount_staked = rewards.amount_staked;
+        self.weighted_stake = rewards.weighted_stake;
+        self.lock_tier = rewards.lock_tier;
+        self.lock_until = rewards.lock_until;
+        self.last_updated_at = rewards.last_updated_at;
+        *self.crop_id.borrow_mut() = rewards.crop_id;
+        *self.reward_debt.borrow_mut() = rewards.reward_debt;
+        *self.amount_owed.borrow_mut() = rewards.amount_owed;
+        *self.crop_finished.borrow_mut() = rewards.crop_finished;
+        *self.archived_owed.borrow_mut() = rewards.archived_owed;
     }
+}
 
-    if !(crop_vault.mint == signer_reward.mint) {
-        panic!("Wrong mint");
-    }
+#[account]
+#[derive(Debug)]
+pub struct Vesting {
+    pub stake: Pubkey,
+    pub crop_id: u64,
+    pub crop_vault: Pubkey,
+    pub vesting_amount: [u64; 4],
+    pub vested_amount: u64,
+    pub vested_until: [u64; 4],
+    pub vesting_until: [u64; 4],
+}
 
-    if !(farm.borrow().crop_created_at.borrow()[farm
-        .borrow()
-        .crop_created_at
-        .wrapped_index((crop_index as i128) as i128)]
-        == 0)
-    {
-        panic!("Crop already active at this index");
+impl<'info, 'entrypoint> Vesting {
+    pub fn load(
+        account: &'entrypoint mut Box<Account<'info, Self>>,
+        programs_map: &'entrypoint ProgramsMap<'info>,
+    ) -> Mutable<LoadedVesting<'info, 'entrypoint>> {
+        let stake = account.stake.clone();
+        let crop_id = account.crop_id;
+        let crop_vault = account.crop_vault.clone();
+        let vesting_amount = Mutable::new(account.vesting_amount.clone());
+        let vested_amount = account.vested_amount;
+        let vested_until = Mutable::new(account.vested_until.clone());
+        let vesting_until = Mutable::new(account.vesting_until.clone());
+
+        Mutable::new(LoadedVesting {
+            __account__: account,
+            __programs__: programs_map,
+            stake,
+            crop_id,
+            crop_vault,
+            vesting_amount,
+            vested_amount,
+            vested_until,
+            vesting_until,
+        })
     }
 
-    let mut current_timestamp =
-        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+    pub fn store(loaded: Mutable<LoadedVesting>) {
+        let mut loaded = loaded.borrow_mut();
+        let stake = loaded.stake.clone();
 
-    let mut precision_scaler = get_precision_scaler();
+        loaded.__account__.stake = stake;
 
-    for mut i in 0..8 {
-        if farm.borrow().crop_created_at.borrow()
-            [farm.borrow().crop_created_at.wrapped_index(i as i128)]
-            == 0
-        {
-            continue;
-        }
+        let crop_id = loaded.crop_id;
 
-        if farm.borrow().last_updated_at
-            > farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)]
-        {
-            continue;
+        loaded.__account__.crop_id = crop_id;
+
+        let crop_vault = loaded.crop_vault.clone();
+
+        loaded.__account__.crop_vault = crop_vault;
+
+        let vesting_amount = loaded.vesting_amount.borrow().clone();
+
+        loaded.__account__.vesting_amount = vesting_amount;
+
+        let vested_amount = loaded.vested_amount;
+
+        loaded.__account__.vested_amount = vested_amount;
+
+        let vested_until = loaded.vested_until.borrow().clone();
+
+        loaded.__account__.vested_until = vested_until;
+
+        let vesting_until = loaded.vesting_until.borrow().clone();
+
+        loaded.__account__.vesting_until = vesting_until;
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedVesting<'info, 'entrypoint> {
+    pub __account__: &'entrypoint mut Box<Account<'info, Vesting>>,
+    pub __programs__: &'entrypoint ProgramsMap<'info>,
+    pub stake: Pubkey,
+    pub crop_id: u64,
+    pub crop_vault: Pubkey,
+    pub vesting_amount: Mutable<[u64; 4]>,
+    pub vested_amount: u64,
+    pub vested_until: Mutable<[u64; 4]>,
+    pub vesting_until: Mutable<[u64; 4]>,
+}
+
+impl<'info, 'entrypoint> LoadedVesting<'info, 'entrypoint> {
+    pub fn schedule(&self) -> VestingSchedule {
+        VestingSchedule {
+            vesting_amount: *self.vesting_amount.borrow(),
+            vested_amount: self.vested_amount,
+            vested_until: *self.vested_until.borrow(),
+            vesting_until: *self.vesting_until.borrow(),
         }
+    }
 
-        if farm.borrow().total_staked_amount == 0 {
-            continue;
+    pub fn set_schedule(&mut self, schedule: &VestingSchedule) {
+        *self.vesting_amount.borrow_mut() = schedule.vesting_amount;
+        self.vested_amount = schedule.vested_amount;
+        *self.vested_until.borrow_mut() = schedule.vested_until;
+        *self.vesting_until.borrow_mut() = schedule.vesting_until;
+    }
+}
+
+impl Farm {
+    /// The id of the crop whose rewards vest into records for slot `index`: the active crop, or
+    /// the archived one when `archived` is set.
+    pub fn vesting_crop_id(&self, index: u8, archived: bool) -> u64 {
+        if archived {
+            self.archived_crop_id[usize::from(index)]
+        } else {
+            self.crop_id[usize::from(index)]
         }
+    }
 
-        let mut current_time = current_timestamp.min(
-            farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)],
-        );
+    /// The vault holding the rewards that vest for slot `index`.
+    pub fn vesting_crop_vault(&self, index: u8, archived: bool) -> Pubkey {
+        if archived {
+            self.archived_crop_vault[usize::from(index)]
+        } else {
+            self.crop_vault[usize::from(index)]
+        }
+    }
 
-        if farm.borrow().total_staked_amount > <u64 as TryFrom<_>>::try_from(0).unwrap() {
-            let mut time_diff = current_time - farm.borrow().last_updated_at;
-            let mut rewards = <u128 as TryFrom<_>>::try_from(
-                (time_diff
-                    * farm.borrow().crop_rewards_per_second.borrow()[farm
-                        .borrow()
-                        .crop_rewards_per_second
-                        .wrapped_index(i as i128)]),
-            )
-            .unwrap()
-                * precision_scaler;
+    /// How long rewards from slot `index` vest for. Zero when they are paid out straight away.
+    pub fn vesting_period(&self, index: u8, archived: bool) -> u64 {
+        if archived {
+            self.archived_vesting_period[usize::from(index)]
+        } else {
+            self.crop_vesting_period[usize::from(index)]
+        }
+    }
+}
 
-            let mut rewards_per_token = rewards / (farm.borrow().total_staked_amount as u128);
-            let mut current_rewards_per_token = farm.borrow().crop_rewards_per_token.borrow()[farm
-                .borrow()
-                .crop_rewards_per_token
-                .wrapped_index(i as i128)];
-
-            index_assign!(
-                farm.borrow_mut().crop_rewards_per_token.borrow_mut(),
-                farm.borrow_mut()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128),
-                current_rewards_per_token
-                    + <u128 as TryFrom<_>>::try_from(rewards_per_token.clone()).unwrap()
-            );
+impl Protocol {
+    /// Whether farms may be created for `mint`. An empty allow-list allows every mint.
+    pub fn allows_stake_mint(&self, mint: &Pubkey) -> bool {
+        self.allowed_stake_mints
+            .iter()
+            .all(|allowed| *allowed == Pubkey::default())
+            || self.allowed_stake_mints.contains(mint)
+    }
+}
 
-            assign!(farm.borrow_mut().last_updated_at, current_time);
+impl Stake {
+    /// The wallet whose token accounts receive this stake's rewards. An unset
+    /// `reward_recipient` means the stake owner.
+    pub fn recipient(&self) -> Pubkey {
+        if self.reward_recipient == Pubkey::default() {
+            self.owner
+        } else {
+            self.reward_recipient
         }
     }
+}
+
+pub fn accept_owner_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    assign!(farm.borrow_mut().owner, signer.key());
+
+    assign!(farm.borrow_mut().pending_owner, Pubkey::default());
+
+    Ok(())
+}
+
+pub fn accrue_farm_rewards<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut now: u64,
+) -> Result<()> {
+    let mut rewards = farm.borrow().rewards();
+
+    accrual::accrue_farm(&mut rewards, now)?;
+
+    farm.borrow_mut().set_rewards(&rewards);
+
+    Ok(())
+}
+
+/// The longest a crop's rewards can vest for.
+pub const MAX_VESTING_PERIOD: u64 = 365 * 24 * 60 * 60;
+
+pub fn add_crop_handler<'info>(
+    mut crop_index: u8,
+    mut reward_amount: u64,
+    mut rewards_per_second: u64,
+    mut start_at: Option<u64>,
+    mut vesting_period: u64,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        (crop_index as usize) < accrual::CROP_COUNT,
+        FarmError::IndexTooHigh
+    );
+
+    require!(
+        farm.borrow().crop_created_at.borrow()[farm
+            .borrow()
+            .crop_created_at
+            .wrapped_index((crop_index as i128) as i128)]
+            == 0,
+        FarmError::CropAlreadyActive
+    );
+
+    require!(rewards_per_second > 0, FarmError::ZeroRewardRate);
+
+    require!(
+        vesting_period <= MAX_VESTING_PERIOD,
+        FarmError::VestingPeriodTooLong
+    );
+
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    // A crop is funded now but may be scheduled to start emitting later
+    let mut crop_start = start_at.unwrap_or(current_timestamp).max(current_timestamp);
 
     token::transfer(
         CpiContext::new(
@@ -364,8 +917,7 @@ pub fn add_crop_handler<'info>(
             },
         ),
         reward_amount.clone(),
-    )
-    .unwrap();
+    )?;
 
     index_assign!(
         farm.borrow_mut().crop_rewards_per_second.borrow_mut(),
@@ -383,6 +935,22 @@ pub fn add_crop_handler<'info>(
         current_timestamp
     );
 
+    index_assign!(
+        farm.borrow_mut().crop_start_at.borrow_mut(),
+        farm.borrow_mut()
+            .crop_start_at
+            .wrapped_index((crop_index as i128) as i128),
+        crop_start
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_vesting_period.borrow_mut(),
+        farm.borrow_mut()
+            .crop_vesting_period
+            .wrapped_index((crop_index as i128) as i128),
+        vesting_period
+    );
+
     index_assign!(
         farm.borrow_mut().crop_stakers_finished.borrow_mut(),
         farm.borrow_mut()
@@ -391,6 +959,64 @@ pub fn add_crop_handler<'info>(
         <u64 as TryFrom<_>>::try_from(0).unwrap()
     );
 
+    index_assign!(
+        farm.borrow_mut().crop_stakers_owed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_stakers_owed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_funded.borrow_mut(),
+        farm.borrow_mut()
+            .crop_funded
+            .wrapped_index((crop_index as i128) as i128),
+        reward_amount
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_allocated.borrow_mut(),
+        farm.borrow_mut()
+            .crop_allocated
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_claimed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_claimed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    assign!(
+        farm.borrow_mut().crop_nonce,
+        farm.borrow()
+            .crop_nonce
+            .checked_add(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
+
+    let mut crop_id = farm.borrow().crop_nonce;
+
+    index_assign!(
+        farm.borrow_mut().crop_id.borrow_mut(),
+        farm.borrow_mut()
+            .crop_id
+            .wrapped_index((crop_index as i128) as i128),
+        crop_id
+    );
+
     index_assign!(
         farm.borrow_mut().crop_vault.borrow_mut(),
         farm.borrow_mut()
@@ -408,7 +1034,9 @@ pub fn add_crop_handler<'info>(
     );
 
     let mut crop_active_time = reward_amount / rewards_per_second;
-    let mut crop_famine_time = current_timestamp + crop_active_time;
+    let mut crop_famine_time = crop_start
+        .checked_add(crop_active_time)
+        .ok_or(FarmError::MathOverflow)?;
 
     index_assign!(
         farm.borrow_mut().crop_end_date.borrow_mut(),
@@ -418,33 +1046,383 @@ pub fn add_crop_handler<'info>(
         crop_famine_time
     );
 
+    index_assign!(
+        farm.borrow_mut().crop_last_updated_at.borrow_mut(),
+        farm.borrow_mut()
+            .crop_last_updated_at
+            .wrapped_index((crop_index as i128) as i128),
+        crop_start
+    );
+
     assign!(farm.borrow_mut().last_updated_at, current_timestamp);
+
+    Ok(())
 }
 
-pub fn create_crop_vault_handler<'info>(
+pub fn claim_vested_handler<'info>(
     mut farm: Mutable<LoadedFarm<'info, '_>>,
-    mut crop_vault: Empty<SeahorseAccount<'info, '_, TokenAccount>>,
-    mut mint: SeahorseAccount<'info, '_, Mint>,
-    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
-    mut signer: SeahorseSigner<'info, '_>,
-) -> () {
-    if !(farm.borrow().owner == signer.key()) {
-        panic!("Wrong signer");
-    }
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut vesting: Mutable<LoadedVesting<'info, '_>>,
+    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+    let mut schedule = vesting.borrow().schedule();
+
+    accrual::vest(&mut schedule, current_timestamp)?;
+
+    let mut amount = schedule.vested_amount;
+
+    require!(amount > 0, FarmError::NothingVested);
+
+    schedule.vested_amount = 0;
+
+    vesting.borrow_mut().set_schedule(&schedule);
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            crop_vault.programs.get("token_program"),
+            token::Transfer {
+                from: crop_vault.to_account_info(),
+                authority: farm_authority.clone(),
+                to: signer_reward.clone().to_account_info(),
+            },
+            &[Mutable::new(vec![
+                "farm-authority".to_string().as_bytes().as_ref(),
+                farm_key.as_ref(),
+                bump.to_le_bytes().as_ref(),
+            ])
+            .borrow()
+            .as_slice()],
+        ),
+        amount,
+    )?;
+
+    Ok(())
+}
+
+pub fn close_farm_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
+    mut farm_authority: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    require!(
+        farm.borrow().total_staked_amount == 0 && farm.borrow().total_unbonding == 0,
+        FarmError::FarmNotEmpty
+    );
+
+    // Vesting rewards are still held in the crop vaults
+    require!(farm.borrow().active_vestings == 0, FarmError::FarmNotEmpty);
+
+    require!(
+        farm.borrow()
+            .crop_created_at
+            .borrow()
+            .iter()
+            .all(|created_at| *created_at == 0),
+        FarmError::FarmNotEmpty
+    );
+
+    require!(
+        farm.borrow()
+            .archived_stakers_pending
+            .borrow()
+            .iter()
+            .all(|pending| *pending == 0),
+        FarmError::ArchivedCropUnclaimed
+    );
+
+    // Vaults to close come in as (vault, destination) pairs: every crop vault the farm created, plus
+    // the fee vault if it has one
+    require!(
+        remaining_accounts.chunks_exact(2).remainder().is_empty(),
+        FarmError::WrongRemainingAccounts
+    );
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+
+    close_vault(
+        &stake_vault,
+        signer_token.to_account_info(),
+        farm_authority.clone(),
+        &farm_key,
+        bump,
+        signer.clone(),
+    )?;
+
+    let mut fee_vault = farm.borrow().fee_vault;
+    let mut fee_vault_closed = fee_vault == Pubkey::default();
+    let mut closed_crop_vaults = Vec::new();
+
+    for mut pair in remaining_accounts.chunks(2) {
+        let mut crop_vault = Account::<TokenAccount>::try_from(&pair[0])?;
+        let mut destination = Account::<TokenAccount>::try_from(&pair[1])?;
+
+        let (mut expected_vault, _) = Pubkey::find_program_address(
+            &[
+                "farm-crop-vault".as_bytes(),
+                farm_key.as_ref(),
+                crop_vault.mint.as_ref(),
+            ],
+            &id(),
+        );
+
+        require!(
+            crop_vault.key() == expected_vault || crop_vault.key() == fee_vault,
+            FarmError::WrongCropVault
+        );
+
+        if crop_vault.key() == fee_vault {
+            fee_vault_closed = true;
+        } else {
+            require!(
+                !closed_crop_vaults.contains(&crop_vault.key()),
+                FarmError::WrongRemainingAccounts
+            );
+
+            closed_crop_vaults.push(crop_vault.key());
+        }
+
+        require!(destination.mint == crop_vault.mint, FarmError::WrongMint);
+        require!(
+            destination.owner == signer.key(),
+            FarmError::NotSignersTokenAccount
+        );
+
+        close_vault(
+            &crop_vault,
+            destination.to_account_info(),
+            farm_authority.clone(),
+            &farm_key,
+            bump,
+            signer.clone(),
+        )?;
+    }
+
+    require!(fee_vault_closed, FarmError::FarmNotEmpty);
+
+    require!(
+        closed_crop_vaults.len() as u64 == farm.borrow().crop_vaults,
+        FarmError::FarmNotEmpty
+    );
+
+    Ok(())
+}
+
+/// Empties a vault owned by the farm's authority PDA into `destination` and closes it, returning
+/// its rent to the signer.
+fn close_vault<'info>(
+    mut vault: &Account<'info, TokenAccount>,
+    mut destination: AccountInfo<'info>,
+    mut farm_authority: AccountInfo<'info>,
+    mut farm_key: &Pubkey,
+    mut bump: u8,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    let mut bump = bump.to_le_bytes();
+    let mut seeds: &[&[u8]] = &[
+        "farm-authority".as_bytes(),
+        farm_key.as_ref(),
+        bump.as_ref(),
+    ];
+
+    if vault.amount > 0 {
+        token::transfer(
+            CpiContext::new_with_signer(
+                signer.programs.get("token_program"),
+                token::Transfer {
+                    from: vault.to_account_info(),
+                    authority: farm_authority.clone(),
+                    to: destination,
+                },
+                &[seeds],
+            ),
+            vault.amount,
+        )?;
+    }
 
+    token::close_account(CpiContext::new_with_signer(
+        signer.programs.get("token_program"),
+        token::CloseAccount {
+            account: vault.to_account_info(),
+            destination: signer.to_account_info(),
+            authority: farm_authority.clone(),
+        },
+        &[seeds],
+    ))?;
+
+    Ok(())
+}
+
+pub fn close_stake_handler<'info>(
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    // An empty stake is not counted towards any of the farm's staker counters, so it can go
+    // without touching the farm.
+    require!(stake.borrow().amount_staked == 0, FarmError::StakeNotEmpty);
+
+    require!(
+        stake
+            .borrow()
+            .unbonding_amount
+            .borrow()
+            .iter()
+            .all(|amount| *amount == 0),
+        FarmError::StakeNotEmpty
+    );
+
+    require!(
+        stake.borrow().active_vestings == 0,
+        FarmError::StakeNotEmpty
+    );
+
+    require!(
+        stake
+            .borrow()
+            .amount_owed
+            .borrow()
+            .iter()
+            .all(|owed| *owed == 0),
+        FarmError::StakeNotEmpty
+    );
+
+    require!(
+        stake
+            .borrow()
+            .archived_owed
+            .borrow()
+            .iter()
+            .all(|owed| *owed == 0),
+        FarmError::StakeNotEmpty
+    );
+
+    Ok(())
+}
+
+pub fn close_vesting_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut vesting: Mutable<LoadedVesting<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        vesting
+            .borrow()
+            .vesting_amount
+            .borrow()
+            .iter()
+            .all(|amount| *amount == 0)
+            && vesting.borrow().vested_amount == 0,
+        FarmError::VestingNotEmpty
+    );
+
+    assign!(
+        farm.borrow_mut().active_vestings,
+        farm.borrow()
+            .active_vestings
+            .checked_sub(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
+
+    assign!(
+        stake.borrow_mut().active_vestings,
+        stake
+            .borrow()
+            .active_vestings
+            .checked_sub(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
+
+    Ok(())
+}
+
+pub fn collect_farm_fees_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut fee_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
+    mut farm_authority: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            fee_vault.programs.get("token_program"),
+            token::Transfer {
+                from: fee_vault.to_account_info(),
+                authority: farm_authority.clone(),
+                to: signer_token.clone().to_account_info(),
+            },
+            &[Mutable::new(vec![
+                "farm-authority".to_string().as_bytes().as_ref(),
+                farm_key.as_ref(),
+                bump.to_le_bytes().as_ref(),
+            ])
+            .borrow()
+            .as_slice()],
+        ),
+        fee_vault.amount,
+    )?;
+
+    Ok(())
+}
+
+pub fn create_crop_vault_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: Empty<SeahorseAccount<'info, '_, TokenAccount>>,
+    mut mint: SeahorseAccount<'info, '_, Mint>,
+    mut farm_authority: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
     crop_vault.account.clone();
+
+    // Counted so the farm can only close once every crop vault has been emptied and closed
+    assign!(
+        farm.borrow_mut().crop_vaults,
+        farm.borrow()
+            .crop_vaults
+            .checked_add(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
+
+    Ok(())
 }
 
+/// The highest deposit or withdrawal fee a farm can charge. Stakes that are locked or unbonding
+/// cannot leave when the fee goes up, so it is capped well below their whole principal.
+pub const MAX_FARM_FEE_BPS: u16 = 1_000;
+
 pub fn create_farm_handler<'info>(
+    mut deposit_fee_bps: u16,
+    mut withdraw_fee_bps: u16,
     mut farm: Empty<Mutable<LoadedFarm<'info, '_>>>,
+    mut farm_authority: AccountInfo<'info>,
     mut stake_mint: SeahorseAccount<'info, '_, Mint>,
     mut stake_vault: Empty<SeahorseAccount<'info, '_, TokenAccount>>,
+    mut fee_vault: Empty<SeahorseAccount<'info, '_, TokenAccount>>,
     mut clock: Sysvar<'info, Clock>,
     mut protocol: Mutable<LoadedProtocol<'info, '_>>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
+) -> Result<()> {
     solana_program::msg!("{}", "Creating farm".to_string());
 
+    require!(
+        deposit_fee_bps <= MAX_FARM_FEE_BPS && withdraw_fee_bps <= MAX_FARM_FEE_BPS,
+        FarmError::InvalidFee
+    );
+
     let mut created_farm = farm.account.clone();
 
     assign!(created_farm.borrow_mut().owner, signer.key());
@@ -463,6 +1441,26 @@ pub fn create_farm_handler<'info>(
 
     assign!(created_farm.borrow_mut().stake_mint, stake_mint.key());
 
+    assign!(created_farm.borrow_mut().deposit_fee_bps, deposit_fee_bps);
+
+    assign!(created_farm.borrow_mut().withdraw_fee_bps, withdraw_fee_bps);
+
+    assign!(created_farm.borrow_mut().fee_vault, fee_vault.account.key());
+
+    // Every vault the farm owns is held by this PDA rather than the shared protocol account, so
+    // a transfer signed for one farm can never move another farm's tokens.
+    assign!(
+        created_farm.borrow_mut().authority_bump,
+        Pubkey::find_program_address(
+            &[
+                "farm-authority".as_bytes(),
+                created_farm.borrow().__account__.key().as_ref()
+            ],
+            &id()
+        )
+        .1
+    );
+
     assign!(
         created_farm.borrow_mut().last_updated_at,
         <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap()
@@ -472,12 +1470,27 @@ pub fn create_farm_handler<'info>(
         created_farm.borrow_mut().total_staked_amount,
         <u64 as TryFrom<_>>::try_from(0).unwrap()
     );
+
+    Ok(())
+}
+
+/// Gives a farm created before deposit and withdrawal fees existed the fee vault that staking and
+/// unstaking now expect.
+pub fn create_fee_vault_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut fee_vault: Empty<SeahorseAccount<'info, '_, TokenAccount>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    // Anyone can create it, since stakers of farms from before fees cannot withdraw without it
+    assign!(farm.borrow_mut().fee_vault, fee_vault.account.key());
+
+    Ok(())
 }
 
 pub fn create_protocol_handler<'info>(
     mut protocol: Empty<Mutable<LoadedProtocol<'info, '_>>>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
+) -> Result<()> {
     let mut created_protocol = protocol.account.clone();
 
     assign!(
@@ -490,6 +1503,10 @@ pub fn create_protocol_handler<'info>(
         )
         .1
     );
+
+    assign!(created_protocol.borrow_mut().admin, signer.key());
+
+    Ok(())
 }
 
 pub fn create_stake_handler<'info>(
@@ -497,7 +1514,7 @@ pub fn create_stake_handler<'info>(
     mut farm: Mutable<LoadedFarm<'info, '_>>,
     mut clock: Sysvar<'info, Clock>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
+) -> Result<()> {
     let mut created_stake = stake.account.clone();
 
     assign!(
@@ -551,229 +1568,971 @@ pub fn create_stake_handler<'info>(
                 .wrapped_index(i as i128),
             <u64 as TryFrom<_>>::try_from(0).unwrap()
         );
+
+        index_assign!(
+            created_stake.borrow_mut().crop_id.borrow_mut(),
+            created_stake.borrow_mut().crop_id.wrapped_index(i as i128),
+            farm.borrow().crop_id.borrow()[farm.borrow().crop_id.wrapped_index(i as i128)]
+        );
+
+        index_assign!(
+            created_stake.borrow_mut().crop_finished.borrow_mut(),
+            created_stake
+                .borrow_mut()
+                .crop_finished
+                .wrapped_index(i as i128),
+            false
+        );
     }
+
+    Ok(())
 }
 
-pub fn gather_rewards_handler<'info>(
+pub fn create_vesting_handler<'info>(
     mut crop_index: u8,
+    mut archived: bool,
+    mut vesting: Empty<Mutable<LoadedVesting<'info, '_>>>,
     mut farm: Mutable<LoadedFarm<'info, '_>>,
-    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
     mut stake: Mutable<LoadedStake<'info, '_>>,
-    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
-    mut clock: Sysvar<'info, Clock>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
     mut signer: SeahorseSigner<'info, '_>,
-    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
-) -> () {
-    if !(stake.borrow().farm == farm.borrow().__account__.key()) {
-        panic!("Wrong farm");
-    }
-
-    if !(farm.borrow().crop_vault.borrow()[farm
-        .borrow()
-        .crop_vault
-        .wrapped_index((crop_index as i128) as i128)]
-        == crop_vault.key())
-    {
-        panic!("Wrong stake vault");
-    }
-
-    if !(signer_reward.mint == crop_vault.mint) {
-        panic!("Wrong mint");
-    }
+) -> Result<()> {
+    let mut created_vesting = vesting.account.clone();
 
-    if !((stake.borrow().owner == signer.key()) || (farm.borrow().owner == signer.key())) {
-        panic!("Wrong signer");
-    }
+    assign!(
+        created_vesting.borrow_mut().stake,
+        stake.borrow().__account__.key()
+    );
 
-    if !(signer_reward.owner == signer.key()) {
-        panic!("Not signers token account");
-    }
+    assign!(
+        created_vesting.borrow_mut().crop_id,
+        farm.borrow()
+            .__account__
+            .vesting_crop_id(crop_index, archived)
+    );
 
-    let mut current_timestamp =
-        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+    // Kept so the vested rewards can still be claimed after the crop is removed
+    assign!(created_vesting.borrow_mut().crop_vault, crop_vault.key());
 
-    let mut precision_scaler = get_precision_scaler();
+    // Counted so neither the farm nor the stake can close while rewards are still vesting
+    assign!(
+        farm.borrow_mut().active_vestings,
+        farm.borrow()
+            .active_vestings
+            .checked_add(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
 
-    for mut i in 0..8 {
-        if farm.borrow().crop_created_at.borrow()
-            [farm.borrow().crop_created_at.wrapped_index(i as i128)]
-            == 0
-        {
-            continue;
-        }
+    assign!(
+        stake.borrow_mut().active_vestings,
+        stake
+            .borrow()
+            .active_vestings
+            .checked_add(1)
+            .ok_or(FarmError::MathOverflow)?
+    );
 
-        if farm.borrow().last_updated_at
-            > farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)]
-        {
-            continue;
-        }
+    Ok(())
+}
 
-        if farm.borrow().total_staked_amount == 0 {
-            continue;
-        }
+pub fn emergency_unstake_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
 
-        let mut current_time = current_timestamp.min(
-            farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)],
-        );
+    // The farm owner can pause their own farm, so only a protocol pause lets a locked stake out
+    // early
+    require!(
+        protocol.borrow().paused || current_timestamp >= stake.borrow().lock_until,
+        FarmError::StakeLocked
+    );
 
-        if farm.borrow().total_staked_amount > <u64 as TryFrom<_>>::try_from(0).unwrap() {
-            let mut time_diff = current_time - farm.borrow().last_updated_at;
-            let mut rewards = <u128 as TryFrom<_>>::try_from(
-                (time_diff
-                    * farm.borrow().crop_rewards_per_second.borrow()[farm
-                        .borrow()
-                        .crop_rewards_per_second
-                        .wrapped_index(i as i128)]),
-            )
-            .unwrap()
-                * precision_scaler;
+    // The farm is still accrued so everyone else keeps what they earned alongside this stake,
+    // but the stake itself is not settled: its pending rewards are forfeited.
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
 
-            let mut rewards_per_token = rewards / (farm.borrow().total_staked_amount as u128);
-            let mut current_rewards_per_token = farm.borrow().crop_rewards_per_token.borrow()[farm
-                .borrow()
-                .crop_rewards_per_token
-                .wrapped_index(i as i128)];
-
-            index_assign!(
-                farm.borrow_mut().crop_rewards_per_token.borrow_mut(),
-                farm.borrow_mut()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128),
-                current_rewards_per_token
-                    + <u128 as TryFrom<_>>::try_from(rewards_per_token.clone()).unwrap()
-            );
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+    let mut amount = accrual::forfeit_stake(&mut farm_rewards, &mut stake_rewards)?;
 
-            assign!(farm.borrow_mut().last_updated_at, current_time);
-        }
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
 
-        if stake.borrow().amount_staked == 0 {
-            continue;
-        }
+    // Anything still unbonding goes back too
+    let mut unbonding_amount = stake
+        .borrow()
+        .unbonding_amount
+        .borrow()
+        .iter()
+        .try_fold(0u64, |total, amount| total.checked_add(*amount))
+        .ok_or(FarmError::MathOverflow)?;
 
-        let mut pending_rewards =
-            ((<u128 as TryFrom<_>>::try_from(stake.borrow().amount_staked.clone()).unwrap()
-                * farm.borrow().crop_rewards_per_token.borrow()[farm
-                    .borrow()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128)])
-                - stake.borrow().reward_debt.borrow()
-                    [stake.borrow().reward_debt.wrapped_index(i as i128)])
-                / precision_scaler;
+    assign!(
+        farm.borrow_mut().total_unbonding,
+        farm.borrow()
+            .total_unbonding
+            .checked_sub(unbonding_amount)
+            .ok_or(FarmError::MathOverflow)?
+    );
 
-        let mut amount_owed = stake.borrow().amount_owed.borrow()
-            [stake.borrow().amount_owed.wrapped_index(i as i128)];
+    assign!(stake.borrow_mut().unbonding_amount, Mutable::new([0; 4]));
 
-        index_assign!(
-            stake.borrow_mut().amount_owed.borrow_mut(),
-            stake.borrow_mut().amount_owed.wrapped_index(i as i128),
-            amount_owed + <u64 as TryFrom<_>>::try_from(pending_rewards.clone()).unwrap()
-        );
-    }
+    assign!(stake.borrow_mut().unbonding_until, Mutable::new([0; 4]));
 
-    let mut rewards_earned = stake.borrow().amount_owed.borrow()[stake
-        .borrow()
-        .amount_owed
-        .wrapped_index((crop_index as i128) as i128)];
+    assign!(stake.borrow_mut().unbonding_fee, Mutable::new([0; 4]));
 
-    index_assign!(
-        stake.borrow_mut().last_gathered_at.borrow_mut(),
-        stake
-            .borrow_mut()
-            .last_gathered_at
-            .wrapped_index((crop_index as i128) as i128),
-        current_timestamp
-    );
+    amount = amount
+        .checked_add(unbonding_amount)
+        .ok_or(FarmError::MathOverflow)?;
 
-    let mut bump = protocol.borrow().bump_seed;
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
 
     token::transfer(
         CpiContext::new_with_signer(
-            crop_vault.programs.get("token_program"),
+            stake_vault.programs.get("token_program"),
             token::Transfer {
-                from: crop_vault.to_account_info(),
-                authority: protocol.borrow().__account__.to_account_info(),
-                to: signer_reward.clone().to_account_info(),
+                from: stake_vault.to_account_info(),
+                authority: farm_authority.clone(),
+                to: signer_token.clone().to_account_info(),
             },
             &[Mutable::new(vec![
-                "protocol".to_string().as_bytes().as_ref(),
+                "farm-authority".to_string().as_bytes().as_ref(),
+                farm_key.as_ref(),
                 bump.to_le_bytes().as_ref(),
             ])
             .borrow()
             .as_slice()],
         ),
-        rewards_earned.clone(),
-    )
-    .unwrap();
+        amount,
+    )?;
 
-    index_assign!(
-        stake.borrow_mut().amount_owed.borrow_mut(),
-        stake
-            .borrow_mut()
-            .amount_owed
-            .wrapped_index((crop_index as i128) as i128),
-        <u64 as TryFrom<_>>::try_from(0).unwrap()
-    );
+    assign!(stake.borrow_mut().last_updated_at, current_timestamp);
 
-    update_reward_debt(farm.clone(), stake.clone());
+    Ok(())
 }
 
-pub fn get_precision_scaler() -> u128 {
-    return <u128 as TryFrom<_>>::try_from(1).unwrap()
-        << <u128 as TryFrom<_>>::try_from(63).unwrap();
+/// Returns `fee_bps` basis points of `amount`, rounded down.
+fn fee_amount(mut amount: u64, mut fee_bps: u16) -> Result<u64> {
+    Ok(
+        u64::try_from(u128::from(amount) * u128::from(fee_bps) / 10_000)
+            .map_err(|_| FarmError::MathOverflow)?,
+    )
 }
 
-pub fn remove_crop_handler<'info>(
-    mut farm: Mutable<LoadedFarm<'info, '_>>,
+pub fn fund_crop_handler<'info>(
     mut crop_index: u8,
+    mut reward_amount: u64,
+    mut rewards_per_second: Option<u64>,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
     mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
     mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
     mut clock: Sysvar<'info, Clock>,
-    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
-    if !(farm.borrow().owner == signer.key()) {
-        panic!("Wrong signer");
-    }
+) -> Result<()> {
+    require!(
+        farm.borrow().crop_created_at.borrow()[farm
+            .borrow()
+            .crop_created_at
+            .wrapped_index((crop_index as i128) as i128)]
+            != 0,
+        FarmError::CropEmpty
+    );
+
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    // Stakers who have claimed everything from an ended crop are counted as finished with it,
+    // so an ended crop is removed and added again rather than revived.
+    require!(
+        farm.borrow().crop_last_updated_at.borrow()[farm
+            .borrow()
+            .crop_last_updated_at
+            .wrapped_index((crop_index as i128) as i128)]
+            < farm.borrow().crop_end_date.borrow()[farm
+                .borrow()
+                .crop_end_date
+                .wrapped_index((crop_index as i128) as i128)],
+        FarmError::CropEnded
+    );
+
+    token::transfer(
+        CpiContext::new(
+            signer_reward.programs.get("token_program"),
+            token::Transfer {
+                from: signer_reward.to_account_info(),
+                authority: signer.clone().to_account_info(),
+                to: crop_vault.clone().to_account_info(),
+            },
+        ),
+        reward_amount.clone(),
+    )?;
+
+    let mut rewards = farm.borrow().rewards();
 
-    if !(crop_index < <u8 as TryFrom<_>>::try_from(5).unwrap()) {
-        panic!("Index too high");
+    rewards.crop_funded[crop_index as usize] = rewards.crop_funded[crop_index as usize]
+        .checked_add(reward_amount)
+        .ok_or(FarmError::MathOverflow)?;
+
+    let mut rewards_per_second =
+        rewards_per_second.unwrap_or(rewards.crop_rewards_per_second[crop_index as usize]);
+
+    accrual::reschedule_crop(
+        &mut rewards,
+        crop_index as usize,
+        current_timestamp,
+        rewards_per_second,
+    )?;
+
+    farm.borrow_mut().set_rewards(&rewards);
+
+    Ok(())
+}
+
+pub fn gather_all_rewards_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+    mut remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+
+    // One (crop vault, destination, treasury) triple per active crop, in slot order
+    let mut active_crops = (0..accrual::CROP_COUNT)
+        .filter(|i| farm.borrow().crop_created_at.borrow()[*i] != 0)
+        .collect::<Vec<_>>();
+
+    require!(
+        remaining_accounts.len() == active_crops.len() * 3,
+        FarmError::WrongRemainingAccounts
+    );
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+    let mut fee_bps = protocol.borrow().fee_bps;
+
+    for (mut i, mut accounts) in active_crops.into_iter().zip(remaining_accounts.chunks(3)) {
+        require!(
+            accounts[0].key() == farm.borrow().crop_vault.borrow()[i],
+            FarmError::WrongCropVault
+        );
+
+        let mut crop_vault = Account::<TokenAccount>::try_from(&accounts[0])?;
+        let mut destination = Account::<TokenAccount>::try_from(&accounts[1])?;
+        let mut treasury = Account::<TokenAccount>::try_from(&accounts[2])?;
+
+        require!(destination.mint == crop_vault.mint, FarmError::WrongMint);
+        require!(
+            destination.owner == stake.borrow().__account__.recipient(),
+            FarmError::WrongRewardRecipient
+        );
+
+        require!(treasury.mint == crop_vault.mint, FarmError::WrongMint);
+        require!(
+            fee_bps == 0 || treasury.owner == protocol.borrow().treasury,
+            FarmError::WrongTreasury
+        );
+
+        let mut rewards_earned = stake.borrow().amount_owed.borrow()[i];
+
+        // Vesting crops are left owed for `vest_rewards`
+        if rewards_earned == 0 || farm.borrow().crop_vesting_period.borrow()[i] > 0 {
+            continue;
+        }
+
+        let mut fee = pay_rewards(
+            &crop_vault,
+            destination.to_account_info(),
+            treasury.to_account_info(),
+            rewards_earned,
+            fee_bps,
+            farm_authority.clone(),
+            &farm_key,
+            bump,
+            &signer,
+        )?;
+
+        stake.borrow_mut().amount_owed.borrow_mut()[i] = 0;
+        stake.borrow_mut().last_gathered_at.borrow_mut()[i] = current_timestamp;
+
+        let mut crop_claimed = farm.borrow().crop_claimed.borrow()[i]
+            .checked_add(rewards_earned)
+            .ok_or(FarmError::MathOverflow)?;
+
+        farm.borrow_mut().crop_claimed.borrow_mut()[i] = crop_claimed;
+
+        let mut crop_fees = farm.borrow().crop_fees.borrow()[i]
+            .checked_add(fee)
+            .ok_or(FarmError::MathOverflow)?;
+
+        farm.borrow_mut().crop_fees.borrow_mut()[i] = crop_fees;
     }
 
-    if !(farm.borrow().crop_created_at.borrow()[farm
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+pub fn gather_archived_rewards_handler<'info>(
+    mut crop_index: u8,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut treasury_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+    let mut rewards_earned =
+        accrual::claim_archived(&mut farm_rewards, &mut stake_rewards, crop_index as usize)?;
+
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+
+    let mut fee = pay_rewards(
+        &crop_vault,
+        signer_reward.to_account_info(),
+        treasury_reward.to_account_info(),
+        rewards_earned,
+        protocol.borrow().fee_bps,
+        farm_authority.clone(),
+        &farm_key,
+        bump,
+        &signer,
+    )?;
+
+    let mut archived_crop_fees = farm.borrow().archived_crop_fees.borrow()[farm
+        .borrow()
+        .archived_crop_fees
+        .wrapped_index((crop_index as i128) as i128)]
+    .checked_add(fee)
+    .ok_or(FarmError::MathOverflow)?;
+
+    index_assign!(
+        farm.borrow_mut().archived_crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .archived_crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        archived_crop_fees
+    );
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+pub fn gather_rewards_handler<'info>(
+    mut crop_index: u8,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut treasury_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+    let mut rewards_earned = stake.borrow().amount_owed.borrow()[stake
+        .borrow()
+        .amount_owed
+        .wrapped_index((crop_index as i128) as i128)];
+
+    index_assign!(
+        stake.borrow_mut().last_gathered_at.borrow_mut(),
+        stake
+            .borrow_mut()
+            .last_gathered_at
+            .wrapped_index((crop_index as i128) as i128),
+        current_timestamp
+    );
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+    let mut fee = pay_rewards(
+        &crop_vault,
+        signer_reward.to_account_info(),
+        treasury_reward.to_account_info(),
+        rewards_earned,
+        protocol.borrow().fee_bps,
+        farm_authority.clone(),
+        &farm_key,
+        bump,
+        &signer,
+    )?;
+
+    index_assign!(
+        stake.borrow_mut().amount_owed.borrow_mut(),
+        stake
+            .borrow_mut()
+            .amount_owed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    let mut crop_claimed = farm.borrow().crop_claimed.borrow()[farm
+        .borrow()
+        .crop_claimed
+        .wrapped_index((crop_index as i128) as i128)]
+    .checked_add(rewards_earned)
+    .ok_or(FarmError::MathOverflow)?;
+
+    index_assign!(
+        farm.borrow_mut().crop_claimed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_claimed
+            .wrapped_index((crop_index as i128) as i128),
+        crop_claimed
+    );
+
+    // `crop_claimed` counts everything paid out of the vault, so the fee is part of it too
+    let mut crop_fees = farm.borrow().crop_fees.borrow()[farm
         .borrow()
-        .crop_created_at
+        .crop_fees
         .wrapped_index((crop_index as i128) as i128)]
-        != 0)
-    {
-        panic!("Crop is empty");
+    .checked_add(fee)
+    .ok_or(FarmError::MathOverflow)?;
+
+    index_assign!(
+        farm.borrow_mut().crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        crop_fees
+    );
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+/// Grows a program-owned account to `space` bytes, zero-filling the new tail and
+/// topping up rent from `signer`. Returns the length the account had before.
+fn grow_account<'info>(
+    mut account: &AccountInfo<'info>,
+    mut space: usize,
+    mut signer: &SeahorseSigner<'info, '_>,
+    mut system_program: &AccountInfo<'info>,
+) -> Result<usize> {
+    let mut old_len = account.data_len();
+
+    if old_len >= space {
+        return Ok(old_len);
     }
 
-    let mut current_time = <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+    let mut rent = Rent::get()?.minimum_balance(space);
+    let mut top_up = rent.saturating_sub(account.lamports());
+
+    if top_up > 0 {
+        solana_program::program::invoke(
+            &solana_program::system_instruction::transfer(&signer.key(), &account.key(), top_up),
+            &[
+                signer.to_account_info(),
+                account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+    }
 
-    if !(current_time
-        > farm.borrow().crop_end_date.borrow()[farm
-            .borrow()
-            .crop_end_date
-            .wrapped_index((crop_index as i128) as i128)])
-    {
-        panic!("Crop not ended");
+    account.realloc(space, true)?;
+
+    Ok(old_len)
+}
+
+/// Resizes a `Farm` created before `crop_last_updated_at` existed and seeds each active crop's
+/// accrual timestamp from the shared `last_updated_at` it used to accrue against. Farms that are
+/// already the current size are left untouched.
+pub fn migrate_farm_handler<'info>(
+    mut farm: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut system_program: AccountInfo<'info>,
+) -> Result<()> {
+    require!(farm.owner == &id(), FarmError::WrongFarm);
+
+    require!(
+        farm.try_borrow_data()?.get(..8) == Some(&Farm::DISCRIMINATOR[..]),
+        FarmError::WrongFarm
+    );
+
+    grow_account(
+        &farm,
+        std::mem::size_of::<Farm>() + 8,
+        &signer,
+        &system_program,
+    )?;
+
+    let mut migrated = Farm::try_deserialize(&mut &farm.try_borrow_data()?[..])?;
+
+    if migrated.total_weighted_stake == 0 {
+        migrated.total_weighted_stake = migrated.total_staked_amount;
+    }
+
+    // Farms from before crop vaults were counted get every vault they still reference
+    if migrated.crop_vaults == 0 {
+        let mut vaults = migrated
+            .crop_vault
+            .iter()
+            .chain(migrated.archived_crop_vault.iter())
+            .filter(|vault| **vault != Pubkey::default())
+            .collect::<Vec<_>>();
+
+        vaults.sort();
+        vaults.dedup();
+
+        migrated.crop_vaults = vaults.len() as u64;
     }
 
-    if !(farm.borrow().stakers
-        == farm.borrow().crop_stakers_finished.borrow()[farm
+    // Each fixup only touches slots that predate the field it fills in, so
+    // running the migration again is a no-op.
+    for mut i in 0..8 {
+        if migrated.crop_created_at[i] == 0 {
+            continue;
+        }
+
+        if migrated.crop_last_updated_at[i] == 0 {
+            migrated.crop_last_updated_at[i] = migrated.last_updated_at;
+        }
+
+        if migrated.crop_start_at[i] == 0 {
+            migrated.crop_start_at[i] = migrated.crop_created_at[i];
+        }
+
+        if migrated.crop_id[i] == 0 {
+            migrated.crop_nonce = migrated
+                .crop_nonce
+                .checked_add(1)
+                .ok_or(FarmError::MathOverflow)?;
+
+            migrated.crop_id[i] = migrated.crop_nonce;
+        }
+
+        // Crops added before funding was tracked are taken to have emitted every second since
+        // they were created, with all of it already claimed. That never reserves less than the
+        // stakers are owed, and leaves the rest of the vault alone.
+        if migrated.crop_funded[i] == 0 {
+            let rate = migrated.crop_rewards_per_second[i];
+            let created_at = migrated.crop_created_at[i];
+
+            migrated.crop_funded[i] =
+                rate.saturating_mul(migrated.crop_end_date[i].saturating_sub(created_at));
+            migrated.crop_allocated[i] =
+                rate.saturating_mul(migrated.crop_last_updated_at[i].saturating_sub(created_at));
+            migrated.crop_claimed[i] = migrated.crop_allocated[i];
+        }
+    }
+
+    migrated.try_serialize(&mut &mut farm.try_borrow_mut_data()?[..])?;
+
+    Ok(())
+}
+
+pub fn migrate_protocol_handler<'info>(
+    mut protocol: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut system_program: AccountInfo<'info>,
+) -> Result<()> {
+    require!(protocol.owner == &id(), FarmError::WrongSigner);
+
+    require!(
+        protocol.try_borrow_data()?.get(..8) == Some(&Protocol::DISCRIMINATOR[..]),
+        FarmError::WrongSigner
+    );
+
+    grow_account(
+        &protocol,
+        std::mem::size_of::<Protocol>() + 8,
+        &signer,
+        &system_program,
+    )?;
+
+    let mut migrated = Protocol::try_deserialize(&mut &protocol.try_borrow_data()?[..])?;
+
+    // Protocols created before there was an admin are handed to the upgrade authority
+    if migrated.admin == Pubkey::default() {
+        migrated.admin = signer.key();
+    }
+
+    migrated.try_serialize(&mut &mut protocol.try_borrow_mut_data()?[..])?;
+
+    Ok(())
+}
+
+/// Serialized length of a stake up to and including `crop_id`. Shorter stakes
+/// were created before crops had ids and have never been counted per crop.
+const STAKE_LEN_WITH_CROP_IDS: usize = 8 + 344 + 64;
+
+pub fn migrate_stake_handler<'info>(
+    mut stake: AccountInfo<'info>,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut system_program: AccountInfo<'info>,
+) -> Result<()> {
+    require!(stake.owner == &id(), FarmError::WrongFarm);
+
+    require!(
+        stake.try_borrow_data()?.get(..8) == Some(&Stake::DISCRIMINATOR[..]),
+        FarmError::WrongFarm
+    );
+
+    let mut old_len = grow_account(
+        &stake,
+        std::mem::size_of::<Stake>() + 8,
+        &signer,
+        &system_program,
+    )?;
+
+    let mut migrated = Stake::try_deserialize(&mut &stake.try_borrow_data()?[..])?;
+
+    require!(
+        migrated.farm == farm.borrow().__account__.key(),
+        FarmError::WrongFarm
+    );
+
+    // Stakes from before lock tiers earn one to one, which is what `migrate_farm` seeds the farm's
+    // weighted total with.
+    if migrated.weighted_stake == 0 {
+        migrated.weighted_stake = migrated.amount_staked;
+    }
+
+    if old_len < STAKE_LEN_WITH_CROP_IDS {
+        // Legacy stakes have been settled against whatever occupies each slot now.
+        migrated.crop_id = *farm.borrow().crop_id.borrow();
+
+        let mut farm_rewards = farm.borrow().rewards();
+        let mut stake_rewards = StakeRewards {
+            amount_staked: migrated.amount_staked,
+            weighted_stake: migrated.weighted_stake,
+            lock_tier: migrated.lock_tier,
+            lock_until: migrated.lock_until,
+            last_updated_at: migrated.last_updated_at,
+            crop_id: migrated.crop_id,
+            reward_debt: migrated.reward_debt,
+            amount_owed: migrated.amount_owed,
+            crop_finished: migrated.crop_finished,
+            archived_owed: migrated.archived_owed,
+        };
+
+        accrual::mark_finished_crops(&farm_rewards, &mut stake_rewards);
+        accrual::update_staker_counts(&mut farm_rewards, &StakeRewards::default(), &stake_rewards)?;
+
+        migrated.crop_finished = stake_rewards.crop_finished;
+        farm.borrow_mut().set_rewards(&farm_rewards);
+    }
+
+    migrated.try_serialize(&mut &mut stake.try_borrow_mut_data()?[..])?;
+
+    Ok(())
+}
+
+/// Hands a vault created while every vault was held by the protocol PDA over to the farm's own
+/// authority PDA. Each of a farm's vaults has to be migrated once before it can pay out again.
+pub fn migrate_vault_authority_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    let mut farm_key = farm.borrow().__account__.key();
+
+    let (mut expected_crop_vault, _) = Pubkey::find_program_address(
+        &[
+            "farm-crop-vault".as_bytes(),
+            farm_key.as_ref(),
+            vault.mint.as_ref(),
+        ],
+        &id(),
+    );
+
+    require!(
+        vault.key() == farm.borrow().stake_vault || vault.key() == expected_crop_vault,
+        FarmError::WrongCropVault
+    );
+
+    // Farms created before they had an authority never stored its bump
+    let (_, mut authority_bump) =
+        Pubkey::find_program_address(&["farm-authority".as_bytes(), farm_key.as_ref()], &id());
+
+    assign!(farm.borrow_mut().authority_bump, authority_bump);
+
+    let mut bump = protocol.borrow().bump_seed;
+
+    token::set_authority(
+        CpiContext::new_with_signer(
+            vault.programs.get("token_program"),
+            token::SetAuthority {
+                current_authority: protocol.borrow().__account__.to_account_info(),
+                account_or_mint: vault.to_account_info(),
+            },
+            &[Mutable::new(vec![
+                "protocol".to_string().as_bytes().as_ref(),
+                bump.to_le_bytes().as_ref(),
+            ])
             .borrow()
-            .crop_stakers_finished
-            .wrapped_index((crop_index as i128) as i128)])
-    {
-        panic!("More stakers need to finish");
+            .as_slice()],
+        ),
+        token::spl_token::instruction::AuthorityType::AccountOwner,
+        Some(farm_authority.key()),
+    )?;
+
+    Ok(())
+}
+
+pub fn pause_farm_handler<'info>(
+    mut paused: bool,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    assign!(farm.borrow_mut().paused, paused);
+
+    Ok(())
+}
+
+/// Pays `amount` of a crop's rewards out of `crop_vault`, sending the protocol's `fee_bps` cut to
+/// `treasury` and the rest to `destination`. Returns the fee taken.
+fn pay_rewards<'info>(
+    mut crop_vault: &Account<'info, TokenAccount>,
+    mut destination: AccountInfo<'info>,
+    mut treasury: AccountInfo<'info>,
+    mut amount: u64,
+    mut fee_bps: u16,
+    mut farm_authority: AccountInfo<'info>,
+    mut farm_key: &Pubkey,
+    mut bump: u8,
+    mut signer: &SeahorseSigner<'info, '_>,
+) -> Result<u64> {
+    let mut fee = fee_amount(amount, fee_bps)?;
+    let mut bump = bump.to_le_bytes();
+    let mut seeds: &[&[u8]] = &[
+        "farm-authority".as_bytes(),
+        farm_key.as_ref(),
+        bump.as_ref(),
+    ];
+
+    for (mut to, mut value) in [(destination, amount - fee), (treasury, fee)] {
+        if value == 0 {
+            continue;
+        }
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                signer.programs.get("token_program"),
+                token::Transfer {
+                    from: crop_vault.to_account_info(),
+                    authority: farm_authority.clone(),
+                    to,
+                },
+                &[seeds],
+            ),
+            value,
+        )?;
     }
 
-    if !(crop_vault.mint == signer_reward.mint) {
-        panic!("Wrong mint");
+    Ok(fee)
+}
+
+pub fn propose_owner_handler<'info>(
+    mut new_owner: Pubkey,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    // Ownership only moves once the new owner accepts, so a mistyped key can be
+    // replaced by proposing again, or withdrawn by proposing the default key.
+    assign!(farm.borrow_mut().pending_owner, new_owner);
+
+    Ok(())
+}
+
+pub fn refresh_stake_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    // Anyone can settle a stake, so a lock that has run out stops weighing on the farm's total
+    // even if its owner never comes back
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+pub fn remove_crop_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_index: u8,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut signer_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut farm_authority: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        (crop_index as usize) < accrual::CROP_COUNT,
+        FarmError::IndexTooHigh
+    );
+
+    require!(
+        farm.borrow().crop_created_at.borrow()[farm
+            .borrow()
+            .crop_created_at
+            .wrapped_index((crop_index as i128) as i128)]
+            != 0,
+        FarmError::CropEmpty
+    );
+
+    let mut current_time = <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    require!(
+        current_time
+            > farm.borrow().crop_end_date.borrow()[farm
+                .borrow()
+                .crop_end_date
+                .wrapped_index((crop_index as i128) as i128)],
+        FarmError::CropNotEnded
+    );
+
+    require!(
+        farm.borrow().archived_stakers_pending.borrow()[farm
+            .borrow()
+            .archived_stakers_pending
+            .wrapped_index((crop_index as i128) as i128)]
+            == 0,
+        FarmError::ArchivedCropUnclaimed
+    );
+
+    accrue_farm_rewards(farm.clone(), current_time)?;
+
+    // Whatever has been emitted but not claimed stays in the vault for the stakers it is owed
+    // to. If nobody is owed anything, the rounding dust left by per-stake settlement goes too.
+    let mut rewards = farm.borrow().rewards();
+    let mut stakers_pending = accrual::archive_crop(&mut rewards, crop_index as usize)?;
+
+    farm.borrow_mut().set_rewards(&rewards);
+
+    let mut crop_funded = farm.borrow().crop_funded.borrow()[farm
+        .borrow()
+        .crop_funded
+        .wrapped_index((crop_index as i128) as i128)];
+    let mut leftover_rewards = if stakers_pending > 0 {
+        crop_funded
+            .checked_sub(
+                farm.borrow().crop_allocated.borrow()[farm
+                    .borrow()
+                    .crop_allocated
+                    .wrapped_index((crop_index as i128) as i128)],
+            )
+            .ok_or(FarmError::MathOverflow)?
+    } else {
+        crop_funded.saturating_sub(
+            farm.borrow().crop_claimed.borrow()[farm
+                .borrow()
+                .crop_claimed
+                .wrapped_index((crop_index as i128) as i128)],
+        )
+    };
+
+    if stakers_pending > 0 {
+        let mut archived_crop_vault = farm.borrow().crop_vault.borrow()[farm
+            .borrow()
+            .crop_vault
+            .wrapped_index((crop_index as i128) as i128)];
+
+        index_assign!(
+            farm.borrow_mut().archived_crop_vault.borrow_mut(),
+            farm.borrow_mut()
+                .archived_crop_vault
+                .wrapped_index((crop_index as i128) as i128),
+            archived_crop_vault
+        );
     }
 
+    // The archived crop carries on the crop's fee total, so fees on every payout reconcile
+    let mut crop_fees = farm.borrow().crop_fees.borrow()[farm
+        .borrow()
+        .crop_fees
+        .wrapped_index((crop_index as i128) as i128)];
+
+    index_assign!(
+        farm.borrow_mut().archived_crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .archived_crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        crop_fees
+    );
+
+    // Archived rewards keep vesting like the crop's did
+    let mut crop_vesting_period = farm.borrow().crop_vesting_period.borrow()[farm
+        .borrow()
+        .crop_vesting_period
+        .wrapped_index((crop_index as i128) as i128)];
+
+    index_assign!(
+        farm.borrow_mut().archived_vesting_period.borrow_mut(),
+        farm.borrow_mut()
+            .archived_vesting_period
+            .wrapped_index((crop_index as i128) as i128),
+        crop_vesting_period
+    );
+
     index_assign!(
         farm.borrow_mut().crop_rewards_per_second.borrow_mut(),
         farm.borrow_mut()
@@ -806,6 +2565,22 @@ pub fn remove_crop_handler<'info>(
         <u64 as TryFrom<_>>::try_from(0).unwrap()
     );
 
+    index_assign!(
+        farm.borrow_mut().crop_start_at.borrow_mut(),
+        farm.borrow_mut()
+            .crop_start_at
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_vesting_period.borrow_mut(),
+        farm.borrow_mut()
+            .crop_vesting_period
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
     index_assign!(
         farm.borrow_mut().crop_stakers_finished.borrow_mut(),
         farm.borrow_mut()
@@ -814,149 +2589,258 @@ pub fn remove_crop_handler<'info>(
         <u64 as TryFrom<_>>::try_from(0).unwrap()
     );
 
-    let mut leftover_rewards = crop_vault.amount;
-    let mut bump = protocol.borrow().bump_seed;
+    index_assign!(
+        farm.borrow_mut().crop_last_updated_at.borrow_mut(),
+        farm.borrow_mut()
+            .crop_last_updated_at
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_stakers_owed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_stakers_owed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_id.borrow_mut(),
+        farm.borrow_mut()
+            .crop_id
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_funded.borrow_mut(),
+        farm.borrow_mut()
+            .crop_funded
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_allocated.borrow_mut(),
+        farm.borrow_mut()
+            .crop_allocated
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_claimed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_claimed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    index_assign!(
+        farm.borrow_mut().crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
 
     token::transfer(
         CpiContext::new_with_signer(
             crop_vault.programs.get("token_program"),
             token::Transfer {
                 from: crop_vault.to_account_info(),
-                authority: protocol.borrow().__account__.to_account_info(),
+                authority: farm_authority.clone(),
                 to: signer_reward.clone().to_account_info(),
             },
             &[Mutable::new(vec![
-                "protocol".to_string().as_bytes().as_ref(),
+                "farm-authority".to_string().as_bytes().as_ref(),
+                farm_key.as_ref(),
                 bump.to_le_bytes().as_ref(),
             ])
             .borrow()
             .as_slice()],
         ),
         leftover_rewards.clone(),
-    )
-    .unwrap();
+    )?;
+
+    Ok(())
+}
+
+pub fn set_crop_rate_handler<'info>(
+    mut crop_index: u8,
+    mut rewards_per_second: u64,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        farm.borrow().crop_created_at.borrow()[farm
+            .borrow()
+            .crop_created_at
+            .wrapped_index((crop_index as i128) as i128)]
+            != 0,
+        FarmError::CropEmpty
+    );
+
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    require!(
+        farm.borrow().crop_last_updated_at.borrow()[farm
+            .borrow()
+            .crop_last_updated_at
+            .wrapped_index((crop_index as i128) as i128)]
+            < farm.borrow().crop_end_date.borrow()[farm
+                .borrow()
+                .crop_end_date
+                .wrapped_index((crop_index as i128) as i128)],
+        FarmError::CropEnded
+    );
+
+    let mut rewards = farm.borrow().rewards();
+
+    accrual::reschedule_crop(
+        &mut rewards,
+        crop_index as usize,
+        current_timestamp,
+        rewards_per_second,
+    )?;
+
+    farm.borrow_mut().set_rewards(&rewards);
+
+    Ok(())
+}
+
+pub fn set_farm_fees_handler<'info>(
+    mut deposit_fee_bps: u16,
+    mut withdraw_fee_bps: u16,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        deposit_fee_bps <= MAX_FARM_FEE_BPS && withdraw_fee_bps <= MAX_FARM_FEE_BPS,
+        FarmError::InvalidFee
+    );
+
+    require!(
+        (deposit_fee_bps == 0 && withdraw_fee_bps == 0)
+            || farm.borrow().fee_vault != Pubkey::default(),
+        FarmError::NoFeeVault
+    );
+
+    assign!(farm.borrow_mut().deposit_fee_bps, deposit_fee_bps);
+
+    assign!(farm.borrow_mut().withdraw_fee_bps, withdraw_fee_bps);
+
+    Ok(())
+}
+
+pub fn set_reward_recipient_handler<'info>(
+    mut reward_recipient: Pubkey,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    assign!(stake.borrow_mut().reward_recipient, reward_recipient);
+
+    Ok(())
+}
+
+/// How many unstakes a stake can have waiting out the unbonding period at once.
+pub const UNBONDING_SLOTS: usize = 4;
+
+/// The longest a farm can hold unstaked tokens. Stakes already in the farm have no say over a new
+/// period, so it is capped at a month.
+pub const MAX_UNBONDING_PERIOD: u64 = 30 * 24 * 60 * 60;
+
+pub fn set_unbonding_period_handler<'info>(
+    mut unbonding_period: u64,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(
+        unbonding_period <= MAX_UNBONDING_PERIOD,
+        FarmError::UnbondingPeriodTooLong
+    );
+
+    assign!(farm.borrow_mut().unbonding_period, unbonding_period);
+
+    Ok(())
+}
+
+pub fn settle_stake_rewards<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut now: u64,
+) -> Result<()> {
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+
+    accrual::sync_crops(&mut farm_rewards, &mut stake_rewards)?;
+    accrual::settle_expired_lock(&mut farm_rewards, &mut stake_rewards, now)?;
+    accrual::settle_stake(&farm_rewards, &mut stake_rewards)?;
+
+    // Everything up to now has been paid for, so a lock that has run out stops boosting the stake
+    // from here on.
+    accrual::update_weight(&mut farm_rewards, &mut stake_rewards, now)?;
+
+    stake_rewards.last_updated_at = now;
+
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
+
+    Ok(())
 }
 
 pub fn stake_tokens_handler<'info>(
     mut amount: u64,
+    mut lock_tier: u8,
     mut farm: Mutable<LoadedFarm<'info, '_>>,
     mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut fee_vault: SeahorseAccount<'info, '_, TokenAccount>,
     mut stake: Mutable<LoadedStake<'info, '_>>,
     mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
     mut clock: Sysvar<'info, Clock>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
-    if !(stake.borrow().farm == farm.borrow().__account__.key()) {
-        panic!("Wrong farm");
-    }
-
-    if !(farm.borrow().stake_vault == stake_vault.key()) {
-        panic!("Wrong stake vault");
-    }
-
-    if !(stake.borrow().owner == signer.key()) {
-        panic!("Wrong signer");
-    }
-
-    if !(signer_token.mint == stake_vault.mint) {
-        panic!("Wrong mint");
-    }
-
-    if !(signer_token.owner == signer.key()) {
-        panic!("Not signers token account");
-    }
-
+) -> Result<()> {
     let mut current_timestamp =
         <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
 
-    let mut precision_scaler = get_precision_scaler();
-
-    for mut i in 0..8 {
-        if farm.borrow().crop_created_at.borrow()
-            [farm.borrow().crop_created_at.wrapped_index(i as i128)]
-            == 0
-        {
-            continue;
-        }
-
-        if farm.borrow().last_updated_at
-            > farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)]
-        {
-            continue;
-        }
-
-        if farm.borrow().total_staked_amount == 0 {
-            continue;
-        }
-
-        let mut current_time = current_timestamp.min(
-            farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)],
-        );
-
-        if farm.borrow().total_staked_amount > <u64 as TryFrom<_>>::try_from(0).unwrap() {
-            let mut time_diff = current_time - farm.borrow().last_updated_at;
-            let mut rewards = <u128 as TryFrom<_>>::try_from(
-                (time_diff
-                    * farm.borrow().crop_rewards_per_second.borrow()[farm
-                        .borrow()
-                        .crop_rewards_per_second
-                        .wrapped_index(i as i128)]),
-            )
-            .unwrap()
-                * precision_scaler;
-
-            let mut rewards_per_token = rewards / (farm.borrow().total_staked_amount as u128);
-            let mut current_rewards_per_token = farm.borrow().crop_rewards_per_token.borrow()[farm
-                .borrow()
-                .crop_rewards_per_token
-                .wrapped_index(i as i128)];
-
-            index_assign!(
-                farm.borrow_mut().crop_rewards_per_token.borrow_mut(),
-                farm.borrow_mut()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128),
-                current_rewards_per_token
-                    + <u128 as TryFrom<_>>::try_from(rewards_per_token.clone()).unwrap()
-            );
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
 
-            assign!(farm.borrow_mut().last_updated_at, current_time);
-        }
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
 
-        if stake.borrow().amount_staked == 0 {
-            continue;
-        }
+    let mut before = stake.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
 
-        let mut pending_rewards =
-            ((<u128 as TryFrom<_>>::try_from(stake.borrow().amount_staked.clone()).unwrap()
-                * farm.borrow().crop_rewards_per_token.borrow()[farm
-                    .borrow()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128)])
-                - stake.borrow().reward_debt.borrow()
-                    [stake.borrow().reward_debt.wrapped_index(i as i128)])
-                / precision_scaler;
+    accrual::lock_stake(&mut stake_rewards, lock_tier, current_timestamp)?;
 
-        let mut amount_owed = stake.borrow().amount_owed.borrow()
-            [stake.borrow().amount_owed.wrapped_index(i as i128)];
+    stake.borrow_mut().set_rewards(&stake_rewards);
 
-        index_assign!(
-            stake.borrow_mut().amount_owed.borrow_mut(),
-            stake.borrow_mut().amount_owed.wrapped_index(i as i128),
-            amount_owed + <u64 as TryFrom<_>>::try_from(pending_rewards.clone()).unwrap()
-        );
-    }
+    // Only what is left after the deposit fee is staked
+    let mut fee = fee_amount(amount, farm.borrow().deposit_fee_bps)?;
+    let mut net_amount = amount - fee;
 
     assign!(
         farm.borrow_mut().total_staked_amount,
-        farm.borrow().total_staked_amount + amount
+        farm.borrow()
+            .total_staked_amount
+            .checked_add(net_amount)
+            .ok_or(FarmError::MathOverflow)?
     );
 
     assign!(
         stake.borrow_mut().amount_staked,
-        stake.borrow().amount_staked + amount
+        stake
+            .borrow()
+            .amount_staked
+            .checked_add(net_amount)
+            .ok_or(FarmError::MathOverflow)?
     );
 
     token::transfer(
@@ -968,186 +2852,558 @@ pub fn stake_tokens_handler<'info>(
                 to: stake_vault.clone().to_account_info(),
             },
         ),
-        amount.clone(),
-    )
-    .unwrap();
+        net_amount,
+    )?;
+
+    if fee > 0 {
+        token::transfer(
+            CpiContext::new(
+                signer_token.programs.get("token_program"),
+                token::Transfer {
+                    from: signer_token.to_account_info(),
+                    authority: signer.clone().to_account_info(),
+                    to: fee_vault.clone().to_account_info(),
+                },
+            ),
+            fee,
+        )?;
+    }
 
     assign!(
         stake.borrow_mut().last_updated_at,
         <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap()
     );
 
-    update_reward_debt(farm.clone(), stake.clone());
+    update_stake_weight(farm.clone(), stake.clone(), current_timestamp)?;
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+/// Pays the protocol fee on `amount` out of `crop_vault` and starts the rest vesting over
+/// `period`. The vesting rewards stay in the vault until they are claimed.
+fn start_vesting<'info>(
+    mut crop_vault: &Account<'info, TokenAccount>,
+    mut treasury: AccountInfo<'info>,
+    mut vesting: Mutable<LoadedVesting<'info, '_>>,
+    mut amount: u64,
+    mut fee_bps: u16,
+    mut period: u64,
+    mut now: u64,
+    mut farm_authority: AccountInfo<'info>,
+    mut farm_key: &Pubkey,
+    mut bump: u8,
+    mut signer: &SeahorseSigner<'info, '_>,
+) -> Result<u64> {
+    let mut fee = fee_amount(amount, fee_bps)?;
+
+    if fee > 0 {
+        let mut bump = bump.to_le_bytes();
+        let mut seeds: &[&[u8]] = &[
+            "farm-authority".as_bytes(),
+            farm_key.as_ref(),
+            bump.as_ref(),
+        ];
+
+        token::transfer(
+            CpiContext::new_with_signer(
+                signer.programs.get("token_program"),
+                token::Transfer {
+                    from: crop_vault.to_account_info(),
+                    authority: farm_authority,
+                    to: treasury,
+                },
+                &[seeds],
+            ),
+            fee,
+        )?;
+    }
+
+    let mut schedule = vesting.borrow().schedule();
+
+    accrual::add_vesting(&mut schedule, amount - fee, period, now)?;
+
+    vesting.borrow_mut().set_schedule(&schedule);
+
+    Ok(fee)
 }
 
 pub fn unstake_tokens_handler<'info>(
     mut amount: u64,
     mut farm: Mutable<LoadedFarm<'info, '_>>,
     mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut fee_vault: SeahorseAccount<'info, '_, TokenAccount>,
     mut stake: Mutable<LoadedStake<'info, '_>>,
     mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
     mut clock: Sysvar<'info, Clock>,
-    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+    mut farm_authority: AccountInfo<'info>,
     mut signer: SeahorseSigner<'info, '_>,
-) -> () {
-    if !(stake.borrow().farm == farm.borrow().__account__.key()) {
-        panic!("Wrong farm");
-    }
+) -> Result<()> {
+    require!(
+        amount <= stake.borrow().amount_staked,
+        FarmError::InsufficientStake
+    );
 
-    if !(farm.borrow().stake_vault == stake_vault.key()) {
-        panic!("Wrong stake vault");
-    }
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
 
-    if !(stake.borrow().owner == signer.key()) {
-        panic!("Wrong signer");
-    }
+    require!(
+        current_timestamp >= stake.borrow().lock_until,
+        FarmError::StakeLocked
+    );
 
-    if !(signer_token.mint == stake_vault.mint) {
-        panic!("Wrong mint");
-    }
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+
+    let mut unbonding_period = farm.borrow().unbonding_period;
+    let mut fee = fee_amount(amount, farm.borrow().withdraw_fee_bps)?;
+
+    if unbonding_period == 0 {
+        withdraw_stake(
+            amount,
+            fee,
+            farm.clone(),
+            stake_vault.clone(),
+            fee_vault.clone(),
+            signer_token.to_account_info(),
+            farm_authority.clone(),
+        )?;
+    } else {
+        // The tokens stop earning now but stay in the vault until the period is over. Each unstake
+        // waits in its own slot, so unstaking again never holds back tokens queued earlier. The fee
+        // is fixed now, so a later change to it cannot reach tokens that are waiting to leave.
+        let mut slot = stake
+            .borrow()
+            .unbonding_amount
+            .borrow()
+            .iter()
+            .position(|queued| *queued == 0)
+            .ok_or(FarmError::UnbondingQueueFull)?;
+
+        stake.borrow().unbonding_amount.borrow_mut()[slot] = amount;
 
-    if !(signer_token.owner == signer.key()) {
-        panic!("Not signers token account");
+        stake.borrow().unbonding_until.borrow_mut()[slot] = current_timestamp
+            .checked_add(unbonding_period)
+            .ok_or(FarmError::MathOverflow)?;
+
+        stake.borrow().unbonding_fee.borrow_mut()[slot] = fee;
+
+        assign!(
+            farm.borrow_mut().total_unbonding,
+            farm.borrow()
+                .total_unbonding
+                .checked_add(amount)
+                .ok_or(FarmError::MathOverflow)?
+        );
     }
 
-    let mut precision_scaler = get_precision_scaler();
+    assign!(
+        farm.borrow_mut().total_staked_amount,
+        farm.borrow()
+            .total_staked_amount
+            .checked_sub(amount)
+            .ok_or(FarmError::MathOverflow)?
+    );
+
+    assign!(
+        stake.borrow_mut().last_updated_at,
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap()
+    );
+
+    assign!(
+        stake.borrow_mut().amount_staked,
+        stake.borrow().amount_staked - amount
+    );
+
+    update_stake_weight(farm.clone(), stake.clone(), current_timestamp)?;
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+/// The highest protocol fee on rewards. It applies to rewards stakers have already earned, so it
+/// is capped at a quarter of every payout.
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 2_500;
+
+pub fn update_protocol_handler<'info>(
+    mut admin: Pubkey,
+    mut paused: bool,
+    mut fee_bps: u16,
+    mut treasury: Pubkey,
+    mut allowed_stake_mints: [Pubkey; 8],
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    require!(fee_bps <= MAX_PROTOCOL_FEE_BPS, FarmError::InvalidFee);
+
+    assign!(protocol.borrow_mut().admin, admin);
+
+    assign!(protocol.borrow_mut().paused, paused);
+
+    assign!(protocol.borrow_mut().fee_bps, fee_bps);
+
+    assign!(protocol.borrow_mut().treasury, treasury);
+
+    assign!(
+        protocol.borrow_mut().allowed_stake_mints,
+        Mutable::new(allowed_stake_mints)
+    );
+
+    Ok(())
+}
+
+pub fn vest_archived_rewards_handler<'info>(
+    mut crop_index: u8,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut vesting: Mutable<LoadedVesting<'info, '_>>,
+    mut treasury_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+) -> Result<()> {
     let mut current_timestamp =
         <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
 
-    let mut precision_scaler = get_precision_scaler();
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
 
-    for mut i in 0..8 {
-        if farm.borrow().crop_created_at.borrow()
-            [farm.borrow().crop_created_at.wrapped_index(i as i128)]
-            == 0
-        {
-            continue;
-        }
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
 
-        if farm.borrow().last_updated_at
-            > farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)]
-        {
-            continue;
-        }
+    let mut before = stake.borrow().rewards();
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+    let mut rewards_earned =
+        accrual::claim_archived(&mut farm_rewards, &mut stake_rewards, crop_index as usize)?;
 
-        if farm.borrow().total_staked_amount == 0 {
-            continue;
-        }
+    require!(rewards_earned > 0, FarmError::NothingToVest);
 
-        let mut current_time = current_timestamp.min(
-            farm.borrow().crop_end_date.borrow()
-                [farm.borrow().crop_end_date.wrapped_index(i as i128)],
-        );
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
 
-        if farm.borrow().total_staked_amount > <u64 as TryFrom<_>>::try_from(0).unwrap() {
-            let mut time_diff = current_time - farm.borrow().last_updated_at;
-            let mut rewards = <u128 as TryFrom<_>>::try_from(
-                (time_diff
-                    * farm.borrow().crop_rewards_per_second.borrow()[farm
-                        .borrow()
-                        .crop_rewards_per_second
-                        .wrapped_index(i as i128)]),
-            )
-            .unwrap()
-                * precision_scaler;
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+    let mut vesting_period = farm.borrow().archived_vesting_period.borrow()[farm
+        .borrow()
+        .archived_vesting_period
+        .wrapped_index((crop_index as i128) as i128)];
 
-            let mut rewards_per_token = rewards / (farm.borrow().total_staked_amount as u128);
-            let mut current_rewards_per_token = farm.borrow().crop_rewards_per_token.borrow()[farm
-                .borrow()
-                .crop_rewards_per_token
-                .wrapped_index(i as i128)];
-
-            index_assign!(
-                farm.borrow_mut().crop_rewards_per_token.borrow_mut(),
-                farm.borrow_mut()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128),
-                current_rewards_per_token
-                    + <u128 as TryFrom<_>>::try_from(rewards_per_token.clone()).unwrap()
-            );
+    let mut fee = start_vesting(
+        &crop_vault,
+        treasury_reward.to_account_info(),
+        vesting.clone(),
+        rewards_earned,
+        protocol.borrow().fee_bps,
+        vesting_period,
+        current_timestamp,
+        farm_authority.clone(),
+        &farm_key,
+        bump,
+        &signer,
+    )?;
+
+    let mut archived_crop_fees = farm.borrow().archived_crop_fees.borrow()[farm
+        .borrow()
+        .archived_crop_fees
+        .wrapped_index((crop_index as i128) as i128)]
+    .checked_add(fee)
+    .ok_or(FarmError::MathOverflow)?;
 
-            assign!(farm.borrow_mut().last_updated_at, current_time);
-        }
+    index_assign!(
+        farm.borrow_mut().archived_crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .archived_crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        archived_crop_fees
+    );
 
-        if stake.borrow().amount_staked == 0 {
-            continue;
-        }
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
 
-        let mut pending_rewards =
-            ((<u128 as TryFrom<_>>::try_from(stake.borrow().amount_staked.clone()).unwrap()
-                * farm.borrow().crop_rewards_per_token.borrow()[farm
-                    .borrow()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128)])
-                - stake.borrow().reward_debt.borrow()
-                    [stake.borrow().reward_debt.wrapped_index(i as i128)])
-                / precision_scaler;
+    update_reward_debt(farm.clone(), stake.clone())?;
 
-        let mut amount_owed = stake.borrow().amount_owed.borrow()
-            [stake.borrow().amount_owed.wrapped_index(i as i128)];
+    Ok(())
+}
 
-        index_assign!(
-            stake.borrow_mut().amount_owed.borrow_mut(),
-            stake.borrow_mut().amount_owed.wrapped_index(i as i128),
-            amount_owed + <u64 as TryFrom<_>>::try_from(pending_rewards.clone()).unwrap()
-        );
-    }
+pub fn vest_rewards_handler<'info>(
+    mut crop_index: u8,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut crop_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut vesting: Mutable<LoadedVesting<'info, '_>>,
+    mut treasury_reward: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut signer: SeahorseSigner<'info, '_>,
+    mut farm_authority: AccountInfo<'info>,
+    mut protocol: Mutable<LoadedProtocol<'info, '_>>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
 
-    let mut bump = protocol.borrow().bump_seed;
+    accrue_farm_rewards(farm.clone(), current_timestamp)?;
+
+    settle_stake_rewards(farm.clone(), stake.clone(), current_timestamp)?;
+
+    let mut before = stake.borrow().rewards();
+    let mut rewards_earned = stake.borrow().amount_owed.borrow()[stake
+        .borrow()
+        .amount_owed
+        .wrapped_index((crop_index as i128) as i128)];
+
+    require!(rewards_earned > 0, FarmError::NothingToVest);
+
+    index_assign!(
+        stake.borrow_mut().last_gathered_at.borrow_mut(),
+        stake
+            .borrow_mut()
+            .last_gathered_at
+            .wrapped_index((crop_index as i128) as i128),
+        current_timestamp
+    );
+
+    index_assign!(
+        stake.borrow_mut().amount_owed.borrow_mut(),
+        stake
+            .borrow_mut()
+            .amount_owed
+            .wrapped_index((crop_index as i128) as i128),
+        <u64 as TryFrom<_>>::try_from(0).unwrap()
+    );
+
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
+    let mut vesting_period = farm.borrow().crop_vesting_period.borrow()[farm
+        .borrow()
+        .crop_vesting_period
+        .wrapped_index((crop_index as i128) as i128)];
+
+    let mut fee = start_vesting(
+        &crop_vault,
+        treasury_reward.to_account_info(),
+        vesting.clone(),
+        rewards_earned,
+        protocol.borrow().fee_bps,
+        vesting_period,
+        current_timestamp,
+        farm_authority.clone(),
+        &farm_key,
+        bump,
+        &signer,
+    )?;
+
+    // Vesting rewards are no longer owed by the crop, so they count as claimed from it
+    let mut crop_claimed = farm.borrow().crop_claimed.borrow()[farm
+        .borrow()
+        .crop_claimed
+        .wrapped_index((crop_index as i128) as i128)]
+    .checked_add(rewards_earned)
+    .ok_or(FarmError::MathOverflow)?;
+
+    index_assign!(
+        farm.borrow_mut().crop_claimed.borrow_mut(),
+        farm.borrow_mut()
+            .crop_claimed
+            .wrapped_index((crop_index as i128) as i128),
+        crop_claimed
+    );
+
+    let mut crop_fees = farm.borrow().crop_fees.borrow()[farm
+        .borrow()
+        .crop_fees
+        .wrapped_index((crop_index as i128) as i128)]
+    .checked_add(fee)
+    .ok_or(FarmError::MathOverflow)?;
+
+    index_assign!(
+        farm.borrow_mut().crop_fees.borrow_mut(),
+        farm.borrow_mut()
+            .crop_fees
+            .wrapped_index((crop_index as i128) as i128),
+        crop_fees
+    );
+
+    update_staker_counts(farm.clone(), stake.clone(), &before)?;
+
+    update_reward_debt(farm.clone(), stake.clone())?;
+
+    Ok(())
+}
+
+/// Pays `amount` out of the farm's stake vault to `destination`, keeping `fee` of it back in
+/// `fee_vault`.
+fn withdraw_stake<'info>(
+    mut amount: u64,
+    mut fee: u64,
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut fee_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut destination: AccountInfo<'info>,
+    mut farm_authority: AccountInfo<'info>,
+) -> Result<()> {
+    let mut farm_key = farm.borrow().__account__.key();
+    let mut bump = farm.borrow().authority_bump;
 
     token::transfer(
         CpiContext::new_with_signer(
             stake_vault.programs.get("token_program"),
             token::Transfer {
                 from: stake_vault.to_account_info(),
-                authority: protocol.borrow().__account__.to_account_info(),
-                to: signer_token.clone().to_account_info(),
+                authority: farm_authority.clone(),
+                to: destination,
             },
             &[Mutable::new(vec![
-                "protocol".to_string().as_bytes().as_ref(),
+                "farm-authority".to_string().as_bytes().as_ref(),
+                farm_key.as_ref(),
                 bump.to_le_bytes().as_ref(),
             ])
             .borrow()
             .as_slice()],
         ),
-        amount.clone(),
-    )
-    .unwrap();
+        amount - fee,
+    )?;
+
+    if fee > 0 {
+        token::transfer(
+            CpiContext::new_with_signer(
+                stake_vault.programs.get("token_program"),
+                token::Transfer {
+                    from: stake_vault.to_account_info(),
+                    authority: farm_authority.clone(),
+                    to: fee_vault.clone().to_account_info(),
+                },
+                &[Mutable::new(vec![
+                    "farm-authority".to_string().as_bytes().as_ref(),
+                    farm_key.as_ref(),
+                    bump.to_le_bytes().as_ref(),
+                ])
+                .borrow()
+                .as_slice()],
+            ),
+            fee,
+        )?;
+    }
 
-    assign!(
-        farm.borrow_mut().total_staked_amount,
-        farm.borrow().total_staked_amount - amount
-    );
+    Ok(())
+}
 
-    assign!(
-        stake.borrow_mut().last_updated_at,
-        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap()
+pub fn withdraw_unbonded_handler<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut fee_vault: SeahorseAccount<'info, '_, TokenAccount>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut signer_token: SeahorseAccount<'info, '_, TokenAccount>,
+    mut clock: Sysvar<'info, Clock>,
+    mut farm_authority: AccountInfo<'info>,
+    mut signer: SeahorseSigner<'info, '_>,
+) -> Result<()> {
+    let mut current_timestamp =
+        <u64 as TryFrom<_>>::try_from(clock.unix_timestamp.clone()).unwrap();
+
+    require!(
+        stake
+            .borrow()
+            .unbonding_amount
+            .borrow()
+            .iter()
+            .any(|queued| *queued > 0),
+        FarmError::NothingUnbonding
     );
 
+    // Every slot whose period is over leaves together; the rest keep waiting
+    let mut amount: u64 = 0;
+    let mut fee: u64 = 0;
+
+    for mut i in 0..UNBONDING_SLOTS {
+        let mut queued = stake.borrow().unbonding_amount.borrow()[i];
+
+        if queued == 0 || current_timestamp < stake.borrow().unbonding_until.borrow()[i] {
+            continue;
+        }
+
+        amount = amount.checked_add(queued).ok_or(FarmError::MathOverflow)?;
+        fee = fee
+            .checked_add(stake.borrow().unbonding_fee.borrow()[i])
+            .ok_or(FarmError::MathOverflow)?;
+
+        stake.borrow().unbonding_amount.borrow_mut()[i] = 0;
+        stake.borrow().unbonding_until.borrow_mut()[i] = 0;
+        stake.borrow().unbonding_fee.borrow_mut()[i] = 0;
+    }
+
+    require!(amount > 0, FarmError::StillUnbonding);
+
+    withdraw_stake(
+        amount,
+        fee,
+        farm.clone(),
+        stake_vault.clone(),
+        fee_vault.clone(),
+        signer_token.to_account_info(),
+        farm_authority.clone(),
+    )?;
+
     assign!(
-        stake.borrow_mut().amount_staked,
-        stake.borrow().amount_staked - amount
+        farm.borrow_mut().total_unbonding,
+        farm.borrow()
+            .total_unbonding
+            .checked_sub(amount)
+            .ok_or(FarmError::MathOverflow)?
     );
 
-    update_reward_debt(farm.clone(), stake.clone());
+    Ok(())
+}
+
+pub fn update_staker_counts<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut before: &StakeRewards,
+) -> Result<()> {
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+
+    accrual::mark_finished_crops(&farm_rewards, &mut stake_rewards);
+    accrual::update_staker_counts(&mut farm_rewards, before, &stake_rewards)?;
+
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
+
+    Ok(())
 }
 
 pub fn update_reward_debt<'info>(
     mut farm: Mutable<LoadedFarm<'info, '_>>,
     mut stake: Mutable<LoadedStake<'info, '_>>,
-) -> () {
-    let mut precision_scaler = get_precision_scaler();
+) -> Result<()> {
+    let mut rewards = stake.borrow().rewards();
 
-    for mut i in 0..8 {
-        index_assign!(
-            stake.borrow_mut().reward_debt.borrow_mut(),
-            stake.borrow_mut().reward_debt.wrapped_index(i as i128),
-            (stake.borrow().amount_staked as u128)
-                * farm.borrow().crop_rewards_per_token.borrow()[farm
-                    .borrow()
-                    .crop_rewards_per_token
-                    .wrapped_index(i as i128)]
-        );
-    }
+    accrual::update_reward_debt(&farm.borrow().rewards(), &mut rewards)?;
+
+    stake.borrow_mut().set_rewards(&rewards);
+
+    Ok(())
+}
+
+pub fn update_stake_weight<'info>(
+    mut farm: Mutable<LoadedFarm<'info, '_>>,
+    mut stake: Mutable<LoadedStake<'info, '_>>,
+    mut now: u64,
+) -> Result<()> {
+    let mut farm_rewards = farm.borrow().rewards();
+    let mut stake_rewards = stake.borrow().rewards();
+
+    accrual::update_weight(&mut farm_rewards, &mut stake_rewards, now)?;
+
+    farm.borrow_mut().set_rewards(&farm_rewards);
+    stake.borrow_mut().set_rewards(&stake_rewards);
+
+    Ok(())
 }
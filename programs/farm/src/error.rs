@@ -0,0 +1,87 @@
+use anchor_lang::prelude::*;
+
+#[error_code]
+pub enum FarmError {
+    #[msg("Wrong signer")]
+    WrongSigner,
+    #[msg("Index too high")]
+    IndexTooHigh,
+    #[msg("Wrong mint")]
+    WrongMint,
+    #[msg("Crop already active at this index")]
+    CropAlreadyActive,
+    #[msg("Crop is empty")]
+    CropEmpty,
+    #[msg("Crop not ended")]
+    CropNotEnded,
+    #[msg("More stakers need to finish")]
+    StakersNotFinished,
+    #[msg("Rewards per second must be greater than zero")]
+    ZeroRewardRate,
+    #[msg("Wrong farm")]
+    WrongFarm,
+    #[msg("Wrong stake vault")]
+    WrongStakeVault,
+    #[msg("Wrong crop vault")]
+    WrongCropVault,
+    #[msg("Not signers token account")]
+    NotSignersTokenAccount,
+    #[msg("Amount exceeds staked balance")]
+    InsufficientStake,
+    #[msg("Reward math overflowed")]
+    MathOverflow,
+    #[msg("Stakers still have rewards to claim from the crop last removed from this slot")]
+    ArchivedCropUnclaimed,
+    #[msg("Crop has already ended")]
+    CropEnded,
+    #[msg("Expected a crop vault and destination for every active crop")]
+    WrongRemainingAccounts,
+    #[msg("Rewards can only be paid to the stake's reward recipient")]
+    WrongRewardRecipient,
+    #[msg("Stake still has tokens staked or rewards to claim")]
+    StakeNotEmpty,
+    #[msg("Farm still has tokens staked or active crops")]
+    FarmNotEmpty,
+    #[msg("Farm is paused")]
+    FarmPaused,
+    #[msg("Farm is not paused")]
+    FarmNotPaused,
+    #[msg("Protocol is paused")]
+    ProtocolPaused,
+    #[msg("Stake mint is not allowed by the protocol")]
+    StakeMintNotAllowed,
+    #[msg("Fee is above the allowed maximum")]
+    InvalidFee,
+    #[msg("Protocol fees must be paid to a token account owned by the treasury")]
+    WrongTreasury,
+    #[msg("Farm has no fee vault to collect fees into")]
+    NoFeeVault,
+    #[msg("Unknown lock tier")]
+    InvalidLockTier,
+    #[msg("A locked stake can only be locked again for at least as long")]
+    LockTooShort,
+    #[msg("Stake is still locked")]
+    StakeLocked,
+    #[msg("Stake has nothing unbonding")]
+    NothingUnbonding,
+    #[msg("Unbonding period has not ended")]
+    StillUnbonding,
+    #[msg("Rewards from this crop vest and must be gathered with vest_rewards")]
+    RewardsVest,
+    #[msg("Rewards from this crop do not vest")]
+    RewardsDoNotVest,
+    #[msg("Nothing has vested yet")]
+    NothingVested,
+    #[msg("Vesting still holds rewards")]
+    VestingNotEmpty,
+    #[msg("Unbonding period is above the allowed maximum")]
+    UnbondingPeriodTooLong,
+    #[msg("Stake has no rewards to vest")]
+    NothingToVest,
+    #[msg("Vesting period is above the allowed maximum")]
+    VestingPeriodTooLong,
+    #[msg("Stake already has as many unstakes waiting as it can hold")]
+    UnbondingQueueFull,
+    #[msg("Vesting already holds as many payouts as it can")]
+    VestingFull,
+}
@@ -2,7 +2,9 @@
 #![allow(unused_variables)]
 #![allow(unused_mut)]
 
+pub mod accrual;
 pub mod dot;
+pub mod error;
 
 use anchor_lang::prelude::*;
 use anchor_spl::{
@@ -11,6 +13,7 @@ use anchor_spl::{
 };
 
 use dot::program::*;
+use error::FarmError;
 use std::{cell::RefCell, rc::Rc};
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
@@ -169,16 +172,66 @@ mod farm {
     use std::collections::HashMap;
 
     #[derive(Accounts)]
-    # [instruction (crop_index : u8 , reward_amount : u64 , rewards_per_second : u64)]
-    pub struct AddCrop<'info> {
-        #[account(mut)]
+    pub struct AcceptOwner<'info> {
+        #[account(
+            mut,
+            constraint = farm.pending_owner == signer.key() @ FarmError::WrongSigner,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
         #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn accept_owner(ctx: Context<AcceptOwner>) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        accept_owner_handler(farm.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8 , reward_amount : u64 , rewards_per_second : u64 , start_at : Option<u64> , vesting_period : u64)]
+    pub struct AddCrop<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            seeds = [b"farm-crop-vault", farm.key().as_ref(), crop_vault.mint.as_ref()],
+            bump,
+            token::authority = farm_authority,
+        )]
         pub crop_vault: Box<Account<'info, TokenAccount>>,
-        #[account(mut)]
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            token::authority = signer,
+        )]
         pub signer_reward: Box<Account<'info, TokenAccount>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
         #[account(mut)]
         pub signer: Signer<'info>,
         pub token_program: Program<'info, Token>,
@@ -189,6 +242,8 @@ mod farm {
         crop_index: u8,
         reward_amount: u64,
         rewards_per_second: u64,
+        start_at: Option<u64>,
+        vesting_period: u64,
     ) -> Result<()> {
         let mut programs = HashMap::new();
 
@@ -219,28 +274,313 @@ mod farm {
             crop_index,
             reward_amount,
             rewards_per_second,
+            start_at,
+            vesting_period,
+            farm.clone(),
+            crop_vault.clone(),
+            signer_reward.clone(),
+            clock.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct ClaimVested<'info> {
+        #[account(constraint = !farm.paused @ FarmError::FarmPaused)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            address = vesting.crop_vault @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            constraint = stake.owner == signer.key() || farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            seeds = [b"vesting", stake.key().as_ref(), vesting.crop_id.to_le_bytes().as_ref()],
+            bump,
+        )]
+        pub vesting: Box<Account<'info, dot::program::Vesting>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = signer_reward.owner == stake.recipient() @ FarmError::WrongRewardRecipient,
+        )]
+        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn claim_vested(ctx: Context<ClaimVested>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let vesting = dot::program::Vesting::load(&mut ctx.accounts.vesting, &programs_map);
+        let signer_reward = SeahorseAccount {
+            account: &ctx.accounts.signer_reward,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        claim_vested_handler(
             farm.clone(),
             crop_vault.clone(),
+            vesting.clone(),
             signer_reward.clone(),
             clock.clone(),
             signer.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+        )?;
+
+        dot::program::Vesting::store(vesting);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct CloseFarm<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+            has_one = stake_vault @ FarmError::WrongStakeVault,
+            close = signer,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut, token::authority = farm_authority)]
+        pub stake_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
+        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn close_farm<'info>(ctx: Context<'_, '_, '_, 'info, CloseFarm<'info>>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
         );
 
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake_vault = SeahorseAccount {
+            account: &ctx.accounts.stake_vault,
+            programs: &programs_map,
+        };
+
+        let signer_token = SeahorseAccount {
+            account: &ctx.accounts.signer_token,
+            programs: &programs_map,
+        };
+
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        close_farm_handler(
+            farm.clone(),
+            stake_vault.clone(),
+            signer_token.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            signer.clone(),
+            ctx.remaining_accounts,
+        )?;
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct CloseStake<'info> {
+        #[doc = "CHECK: Only used to derive the stake address, so stakes can still be closed after their farm is."]
+        pub farm: UncheckedAccount<'info>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            close = signer,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn close_stake(ctx: Context<CloseStake>) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        close_stake_handler(stake.clone(), signer.clone())?;
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct CloseVesting<'info> {
+        #[account(mut)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            seeds = [b"vesting", stake.key().as_ref(), vesting.crop_id.to_le_bytes().as_ref()],
+            bump,
+            close = signer,
+        )]
+        pub vesting: Box<Account<'info, dot::program::Vesting>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn close_vesting(ctx: Context<CloseVesting>) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let vesting = dot::program::Vesting::load(&mut ctx.accounts.vesting, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        close_vesting_handler(farm.clone(), stake.clone(), vesting.clone(), signer.clone())?;
+
         dot::program::Farm::store(farm);
 
+        dot::program::Stake::store(stake);
+
         return Ok(());
     }
 
     #[derive(Accounts)]
-    pub struct CreateCropVault<'info> {
+    pub struct CollectFarmFees<'info> {
+        #[account(
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut, seeds = [b"farm-fee-vault", farm.key().as_ref()], bump)]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
+        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
         #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn collect_farm_fees(ctx: Context<CollectFarmFees>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let fee_vault = SeahorseAccount {
+            account: &ctx.accounts.fee_vault,
+            programs: &programs_map,
+        };
+
+        let signer_token = SeahorseAccount {
+            account: &ctx.accounts.signer_token,
+            programs: &programs_map,
+        };
+
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        collect_farm_fees_handler(
+            farm.clone(),
+            fee_vault.clone(),
+            signer_token.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            signer.clone(),
+        )?;
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct CreateCropVault<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
-        # [account (init , payer = signer , seeds = ["farm-crop-vault" . as_bytes () . as_ref () , farm . key () . as_ref () , mint . key () . as_ref ()] , bump , token :: mint = mint , token :: authority = protocol)]
+        # [account (init , payer = signer , seeds = ["farm-crop-vault" . as_bytes () . as_ref () , farm . key () . as_ref () , mint . key () . as_ref ()] , bump , token :: mint = mint , token :: authority = farm_authority)]
         pub crop_vault: Box<Account<'info, TokenAccount>>,
         #[account(mut)]
         pub mint: Box<Account<'info, Mint>>,
-        #[account(mut)]
-        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
         #[account(mut)]
         pub signer: Signer<'info>,
         pub rent: Sysvar<'info, Rent>,
@@ -276,7 +616,6 @@ mod farm {
             programs: &programs_map,
         };
 
-        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
         let signer = SeahorseSigner {
             account: &ctx.accounts.signer,
             programs: &programs_map,
@@ -286,14 +625,12 @@ mod farm {
             farm.clone(),
             crop_vault.clone(),
             mint.clone(),
-            protocol.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
             signer.clone(),
-        );
+        )?;
 
         dot::program::Farm::store(farm);
 
-        dot::program::Protocol::store(protocol);
-
         return Ok(());
     }
 
@@ -301,13 +638,28 @@ mod farm {
     pub struct CreateFarm<'info> {
         # [account (init , space = std :: mem :: size_of :: < dot :: program :: Farm > () + 8 , payer = signer , seeds = ["farm" . as_bytes () . as_ref () , signer . key () . as_ref () , stake_mint . key () . as_ref ()] , bump)]
         pub farm: Box<Account<'info, dot::program::Farm>>,
-        #[account(mut)]
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            mut,
+            constraint = protocol.allows_stake_mint(&stake_mint.key()) @ FarmError::StakeMintNotAllowed,
+        )]
         pub stake_mint: Box<Account<'info, Mint>>,
-        # [account (init , payer = signer , seeds = ["farm-stake-vault" . as_bytes () . as_ref () , signer . key () . as_ref () , stake_mint . key () . as_ref ()] , bump , token :: mint = stake_mint , token :: authority = protocol)]
+        # [account (init , payer = signer , seeds = ["farm-stake-vault" . as_bytes () . as_ref () , signer . key () . as_ref () , stake_mint . key () . as_ref ()] , bump , token :: mint = stake_mint , token :: authority = farm_authority)]
         pub stake_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            init,
+            payer = signer,
+            seeds = [b"farm-fee-vault", farm.key().as_ref()],
+            bump,
+            token::mint = stake_mint,
+            token::authority = farm_authority,
+        )]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
-        #[account(mut)]
+        #[account(mut, seeds = [b"protocol"], bump = protocol.bump_seed)]
         pub protocol: Box<Account<'info, dot::program::Protocol>>,
         #[account(mut)]
         pub signer: Signer<'info>,
@@ -316,7 +668,11 @@ mod farm {
         pub token_program: Program<'info, Token>,
     }
 
-    pub fn create_farm(ctx: Context<CreateFarm>) -> Result<()> {
+    pub fn create_farm(
+        ctx: Context<CreateFarm>,
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    ) -> Result<()> {
         let mut programs = HashMap::new();
 
         programs.insert(
@@ -348,6 +704,14 @@ mod farm {
             bump: ctx.bumps.get("stake_vault").map(|bump| *bump),
         };
 
+        let fee_vault = Empty {
+            account: SeahorseAccount {
+                account: &ctx.accounts.fee_vault,
+                programs: &programs_map,
+            },
+            bump: ctx.bumps.get("fee_vault").map(|bump| *bump),
+        };
+
         let clock = &ctx.accounts.clock.clone();
         let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
         let signer = SeahorseSigner {
@@ -356,13 +720,17 @@ mod farm {
         };
 
         create_farm_handler(
+            deposit_fee_bps,
+            withdraw_fee_bps,
             farm.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
             stake_mint.clone(),
             stake_vault.clone(),
+            fee_vault.clone(),
             clock.clone(),
             protocol.clone(),
             signer.clone(),
-        );
+        )?;
 
         dot::program::Farm::store(farm.account);
 
@@ -371,10 +739,78 @@ mod farm {
         return Ok(());
     }
 
+    #[derive(Accounts)]
+    pub struct CreateFeeVault<'info> {
+        #[account(mut)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(address = farm.stake_mint @ FarmError::WrongMint)]
+        pub stake_mint: Box<Account<'info, Mint>>,
+        #[account(
+            init,
+            payer = signer,
+            seeds = [b"farm-fee-vault", farm.key().as_ref()],
+            bump,
+            token::mint = stake_mint,
+            token::authority = farm_authority,
+        )]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn create_fee_vault(ctx: Context<CreateFeeVault>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "system_program",
+            ctx.accounts.system_program.to_account_info(),
+        );
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let fee_vault = Empty {
+            account: SeahorseAccount {
+                account: &ctx.accounts.fee_vault,
+                programs: &programs_map,
+            },
+            bump: ctx.bumps.get("fee_vault").map(|bump| *bump),
+        };
+
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        create_fee_vault_handler(farm.clone(), fee_vault.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
     #[derive(Accounts)]
     pub struct CreateProtocol<'info> {
         # [account (init , space = std :: mem :: size_of :: < dot :: program :: Protocol > () + 8 , payer = signer , seeds = ["protocol" . as_bytes () . as_ref ()] , bump)]
         pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(
+            constraint = program.programdata_address()? == Some(program_data.key()) @ FarmError::WrongSigner,
+        )]
+        pub program: Program<'info, crate::program::Farm>,
+        #[account(
+            constraint = program_data.upgrade_authority_address == Some(signer.key()) @ FarmError::WrongSigner,
+        )]
+        pub program_data: Account<'info, ProgramData>,
         #[account(mut)]
         pub signer: Signer<'info>,
         pub rent: Sysvar<'info, Rent>,
@@ -400,7 +836,7 @@ mod farm {
             programs: &programs_map,
         };
 
-        create_protocol_handler(protocol.clone(), signer.clone());
+        create_protocol_handler(protocol.clone(), signer.clone())?;
 
         dot::program::Protocol::store(protocol.account);
 
@@ -442,7 +878,7 @@ mod farm {
             programs: &programs_map,
         };
 
-        create_stake_handler(stake.clone(), farm.clone(), clock.clone(), signer.clone());
+        create_stake_handler(stake.clone(), farm.clone(), clock.clone(), signer.clone())?;
 
         dot::program::Stake::store(stake.account);
 
@@ -452,26 +888,1046 @@ mod farm {
     }
 
     #[derive(Accounts)]
-    # [instruction (crop_index : u8)]
-    pub struct GatherRewards<'info> {
-        #[account(mut)]
+    # [instruction (crop_index : u8 , archived : bool)]
+    pub struct CreateVesting<'info> {
+        #[account(
+            mut,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+            constraint = farm.vesting_period(crop_index, archived) > 0 @ FarmError::RewardsDoNotVest,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
-        #[account(mut)]
+        #[account(
+            constraint = crop_vault.key() == farm.vesting_crop_vault(crop_index, archived) @ FarmError::WrongCropVault,
+        )]
         pub crop_vault: Box<Account<'info, TokenAccount>>,
-        #[account(mut)]
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
         pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            init,
+            space = std::mem::size_of::<dot::program::Vesting>() + 8,
+            payer = signer,
+            seeds = [b"vesting", stake.key().as_ref(), farm.vesting_crop_id(crop_index, archived).to_le_bytes().as_ref()],
+            bump,
+        )]
+        pub vesting: Box<Account<'info, dot::program::Vesting>>,
         #[account(mut)]
-        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        pub signer: Signer<'info>,
+        pub rent: Sysvar<'info, Rent>,
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn create_vesting(
+        ctx: Context<CreateVesting>,
+        crop_index: u8,
+        archived: bool,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "system_program",
+            ctx.accounts.system_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let vesting = Empty {
+            account: dot::program::Vesting::load(&mut ctx.accounts.vesting, &programs_map),
+            bump: ctx.bumps.get("vesting").map(|bump| *bump),
+        };
+
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        create_vesting_handler(
+            crop_index,
+            archived,
+            vesting.clone(),
+            farm.clone(),
+            stake.clone(),
+            crop_vault.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Vesting::store(vesting.account);
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct EmergencyUnstake<'info> {
+        #[account(
+            mut,
+            has_one = stake_vault @ FarmError::WrongStakeVault,
+            constraint = farm.paused || protocol.paused @ FarmError::FarmNotPaused,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub stake_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
+        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(seeds = [b"protocol"], bump = protocol.bump_seed)]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn emergency_unstake(ctx: Context<EmergencyUnstake>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake_vault = SeahorseAccount {
+            account: &ctx.accounts.stake_vault,
+            programs: &programs_map,
+        };
+
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer_token = SeahorseAccount {
+            account: &ctx.accounts.signer_token,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        emergency_unstake_handler(
+            farm.clone(),
+            stake_vault.clone(),
+            stake.clone(),
+            signer_token.clone(),
+            clock.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8 , reward_amount : u64 , rewards_per_second : Option<u64>)]
+    pub struct FundCrop<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            token::authority = signer,
+        )]
+        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn fund_crop(
+        ctx: Context<FundCrop>,
+        crop_index: u8,
+        reward_amount: u64,
+        rewards_per_second: Option<u64>,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let signer_reward = SeahorseAccount {
+            account: &ctx.accounts.signer_reward,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        fund_crop_handler(
+            crop_index,
+            reward_amount,
+            rewards_per_second,
+            farm.clone(),
+            crop_vault.clone(),
+            signer_reward.clone(),
+            clock.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct GatherAllRewards<'info> {
+        #[account(
+            mut,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn gather_all_rewards<'info>(
+        ctx: Context<'_, '_, '_, 'info, GatherAllRewards<'info>>,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let clock = &ctx.accounts.clock.clone();
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        gather_all_rewards_handler(
+            farm.clone(),
+            stake.clone(),
+            clock.clone(),
+            signer.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+            ctx.remaining_accounts,
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8)]
+    pub struct GatherArchivedRewards<'info> {
+        #[account(
+            mut,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = farm.archived_vesting_period[usize::from(crop_index)] == 0 @ FarmError::RewardsVest,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.archived_crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            constraint = stake.owner == signer.key() || farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = signer_reward.owner == stake.recipient() @ FarmError::WrongRewardRecipient,
+        )]
+        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = protocol.fee_bps == 0 || treasury_reward.owner == protocol.treasury @ FarmError::WrongTreasury,
+        )]
+        pub treasury_reward: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn gather_archived_rewards(
+        ctx: Context<GatherArchivedRewards>,
+        crop_index: u8,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer_reward = SeahorseAccount {
+            account: &ctx.accounts.signer_reward,
+            programs: &programs_map,
+        };
+
+        let treasury_reward = SeahorseAccount {
+            account: &ctx.accounts.treasury_reward,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        gather_archived_rewards_handler(
+            crop_index,
+            farm.clone(),
+            crop_vault.clone(),
+            stake.clone(),
+            signer_reward.clone(),
+            treasury_reward.clone(),
+            clock.clone(),
+            signer.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8)]
+    pub struct GatherRewards<'info> {
+        #[account(
+            mut,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = farm.crop_vesting_period[usize::from(crop_index)] == 0 @ FarmError::RewardsVest,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            constraint = stake.owner == signer.key() || farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = signer_reward.owner == stake.recipient() @ FarmError::WrongRewardRecipient,
+        )]
+        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = protocol.fee_bps == 0 || treasury_reward.owner == protocol.treasury @ FarmError::WrongTreasury,
+        )]
+        pub treasury_reward: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn gather_rewards(ctx: Context<GatherRewards>, crop_index: u8) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer_reward = SeahorseAccount {
+            account: &ctx.accounts.signer_reward,
+            programs: &programs_map,
+        };
+
+        let treasury_reward = SeahorseAccount {
+            account: &ctx.accounts.treasury_reward,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        gather_rewards_handler(
+            crop_index,
+            farm.clone(),
+            crop_vault.clone(),
+            stake.clone(),
+            signer_reward.clone(),
+            treasury_reward.clone(),
+            clock.clone(),
+            signer.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct MigrateFarm<'info> {
+        #[doc = "CHECK: Farms created before a layout change are too small to deserialize, so the handler checks the owner and discriminator itself."]
+        #[account(mut)]
+        pub farm: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn migrate_farm(ctx: Context<MigrateFarm>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "system_program",
+            ctx.accounts.system_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        migrate_farm_handler(
+            ctx.accounts.farm.to_account_info(),
+            signer.clone(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct MigrateProtocol<'info> {
+        #[doc = "CHECK: Protocols created before a layout change are too small to deserialize, so the handler checks the owner and discriminator itself."]
+        #[account(mut, seeds = [b"protocol"], bump)]
+        pub protocol: UncheckedAccount<'info>,
+        #[account(
+            constraint = program.programdata_address()? == Some(program_data.key()) @ FarmError::WrongSigner,
+        )]
+        pub program: Program<'info, crate::program::Farm>,
+        #[account(
+            constraint = program_data.upgrade_authority_address == Some(signer.key()) @ FarmError::WrongSigner,
+        )]
+        pub program_data: Account<'info, ProgramData>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn migrate_protocol(ctx: Context<MigrateProtocol>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "system_program",
+            ctx.accounts.system_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        migrate_protocol_handler(
+            ctx.accounts.protocol.to_account_info(),
+            signer.clone(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct MigrateStake<'info> {
+        #[doc = "CHECK: Stakes created before a layout change are too small to deserialize, so the handler checks the owner and discriminator itself."]
+        #[account(mut)]
+        pub stake: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub system_program: Program<'info, System>,
+    }
+
+    pub fn migrate_stake(ctx: Context<MigrateStake>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "system_program",
+            ctx.accounts.system_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        migrate_stake_handler(
+            ctx.accounts.stake.to_account_info(),
+            farm.clone(),
+            signer.clone(),
+            ctx.accounts.system_program.to_account_info(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct MigrateVaultAuthority<'info> {
+        #[account(mut)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut, token::authority = protocol)]
+        pub vault: Box<Account<'info, TokenAccount>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(seeds = [b"protocol"], bump = protocol.bump_seed)]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn migrate_vault_authority(ctx: Context<MigrateVaultAuthority>) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let vault = SeahorseAccount {
+            account: &ctx.accounts.vault,
+            programs: &programs_map,
+        };
+
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        migrate_vault_authority_handler(
+            farm.clone(),
+            vault.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (paused : bool)]
+    pub struct PauseFarm<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() || protocol.admin == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(seeds = [b"protocol"], bump = protocol.bump_seed)]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn pause_farm(ctx: Context<PauseFarm>, paused: bool) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        pause_farm_handler(paused, farm.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (new_owner : Pubkey)]
+    pub struct ProposeOwner<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn propose_owner(ctx: Context<ProposeOwner>, new_owner: Pubkey) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        propose_owner_handler(new_owner, farm.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct RefreshStake<'info> {
+        #[account(mut)]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn refresh_stake(ctx: Context<RefreshStake>) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        refresh_stake_handler(farm.clone(), stake.clone(), clock.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8)]
+    pub struct RemoveCrop<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+        )]
+        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn remove_crop(ctx: Context<RemoveCrop>, crop_index: u8) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
+        );
+
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
+            programs: &programs_map,
+        };
+
+        let signer_reward = SeahorseAccount {
+            account: &ctx.accounts.signer_reward,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        remove_crop_handler(
+            farm.clone(),
+            crop_index,
+            crop_vault.clone(),
+            signer_reward.clone(),
+            clock.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (crop_index : u8 , rewards_per_second : u64)]
+    pub struct SetCropRate<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
         #[account(mut)]
         pub signer: Signer<'info>,
+    }
+
+    pub fn set_crop_rate(
+        ctx: Context<SetCropRate>,
+        crop_index: u8,
+        rewards_per_second: u64,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        set_crop_rate_handler(
+            crop_index,
+            rewards_per_second,
+            farm.clone(),
+            clock.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct SetFarmFees<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn set_farm_fees(
+        ctx: Context<SetFarmFees>,
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        set_farm_fees_handler(
+            deposit_fee_bps,
+            withdraw_fee_bps,
+            farm.clone(),
+            signer.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (reward_recipient : Pubkey)]
+    pub struct SetRewardRecipient<'info> {
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn set_reward_recipient(
+        ctx: Context<SetRewardRecipient>,
+        reward_recipient: Pubkey,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        set_reward_recipient_handler(reward_recipient, stake.clone(), signer.clone())?;
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (unbonding_period : u64)]
+    pub struct SetUnbondingPeriod<'info> {
+        #[account(
+            mut,
+            constraint = farm.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn set_unbonding_period(
+        ctx: Context<SetUnbondingPeriod>,
+        unbonding_period: u64,
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        set_unbonding_period_handler(unbonding_period, farm.clone(), signer.clone())?;
+
+        dot::program::Farm::store(farm);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (amount : u64 , lock_tier : u8)]
+    pub struct StakeTokens<'info> {
+        #[account(
+            mut,
+            has_one = stake_vault @ FarmError::WrongStakeVault,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
         #[account(mut)]
+        pub stake_vault: Box<Account<'info, TokenAccount>>,
+        #[account(mut, seeds = [b"farm-fee-vault", farm.key().as_ref()], bump)]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
+        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
         pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
         pub token_program: Program<'info, Token>,
     }
 
-    pub fn gather_rewards(ctx: Context<GatherRewards>, crop_index: u8) -> Result<()> {
+    pub fn stake_tokens(ctx: Context<StakeTokens>, amount: u64, lock_tier: u8) -> Result<()> {
         let mut programs = HashMap::new();
 
         programs.insert(
@@ -481,14 +1937,19 @@ mod farm {
 
         let programs_map = ProgramsMap(programs);
         let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
-        let crop_vault = SeahorseAccount {
-            account: &ctx.accounts.crop_vault,
+        let stake_vault = SeahorseAccount {
+            account: &ctx.accounts.stake_vault,
+            programs: &programs_map,
+        };
+
+        let fee_vault = SeahorseAccount {
+            account: &ctx.accounts.fee_vault,
             programs: &programs_map,
         };
 
         let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
-        let signer_reward = SeahorseAccount {
-            account: &ctx.accounts.signer_reward,
+        let signer_token = SeahorseAccount {
+            account: &ctx.accounts.signer_token,
             programs: &programs_map,
         };
 
@@ -498,23 +1959,150 @@ mod farm {
             programs: &programs_map,
         };
 
-        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
-
-        gather_rewards_handler(
-            crop_index,
+        stake_tokens_handler(
+            amount,
+            lock_tier,
             farm.clone(),
-            crop_vault.clone(),
+            stake_vault.clone(),
+            fee_vault.clone(),
             stake.clone(),
-            signer_reward.clone(),
+            signer_token.clone(),
             clock.clone(),
             signer.clone(),
-            protocol.clone(),
+        )?;
+
+        dot::program::Farm::store(farm);
+
+        dot::program::Stake::store(stake);
+
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    # [instruction (amount : u64)]
+    pub struct UnstakeTokens<'info> {
+        #[account(
+            mut,
+            has_one = stake_vault @ FarmError::WrongStakeVault,
+        )]
+        pub farm: Box<Account<'info, dot::program::Farm>>,
+        #[account(mut)]
+        pub stake_vault: Box<Account<'info, TokenAccount>>,
+        #[account(mut, seeds = [b"farm-fee-vault", farm.key().as_ref()], bump)]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
+        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[account()]
+        pub clock: Sysvar<'info, Clock>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+        pub token_program: Program<'info, Token>,
+    }
+
+    pub fn unstake_tokens(ctx: Context<UnstakeTokens>, amount: u64) -> Result<()> {
+        let mut programs = HashMap::new();
+
+        programs.insert(
+            "token_program",
+            ctx.accounts.token_program.to_account_info(),
         );
 
+        let programs_map = ProgramsMap(programs);
+        let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
+        let stake_vault = SeahorseAccount {
+            account: &ctx.accounts.stake_vault,
+            programs: &programs_map,
+        };
+
+        let fee_vault = SeahorseAccount {
+            account: &ctx.accounts.fee_vault,
+            programs: &programs_map,
+        };
+
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let signer_token = SeahorseAccount {
+            account: &ctx.accounts.signer_token,
+            programs: &programs_map,
+        };
+
+        let clock = &ctx.accounts.clock.clone();
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        unstake_tokens_handler(
+            amount,
+            farm.clone(),
+            stake_vault.clone(),
+            fee_vault.clone(),
+            stake.clone(),
+            signer_token.clone(),
+            clock.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
+            signer.clone(),
+        )?;
+
         dot::program::Farm::store(farm);
 
         dot::program::Stake::store(stake);
 
+        return Ok(());
+    }
+
+    #[derive(Accounts)]
+    pub struct UpdateProtocol<'info> {
+        #[account(
+            mut,
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = protocol.admin == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[account(mut)]
+        pub signer: Signer<'info>,
+    }
+
+    pub fn update_protocol(
+        ctx: Context<UpdateProtocol>,
+        admin: Pubkey,
+        paused: bool,
+        fee_bps: u16,
+        treasury: Pubkey,
+        allowed_stake_mints: [Pubkey; 8],
+    ) -> Result<()> {
+        let mut programs = HashMap::new();
+        let programs_map = ProgramsMap(programs);
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
+        let signer = SeahorseSigner {
+            account: &ctx.accounts.signer,
+            programs: &programs_map,
+        };
+
+        update_protocol_handler(
+            admin,
+            paused,
+            fee_bps,
+            treasury,
+            allowed_stake_mints,
+            protocol.clone(),
+            signer.clone(),
+        )?;
+
         dot::program::Protocol::store(protocol);
 
         return Ok(());
@@ -522,23 +2110,57 @@ mod farm {
 
     #[derive(Accounts)]
     # [instruction (crop_index : u8)]
-    pub struct RemoveCrop<'info> {
-        #[account(mut)]
+    pub struct VestArchivedRewards<'info> {
+        #[account(
+            mut,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = farm.archived_vesting_period[usize::from(crop_index)] > 0 @ FarmError::RewardsDoNotVest,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
-        #[account(mut)]
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.archived_crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
         pub crop_vault: Box<Account<'info, TokenAccount>>,
-        #[account(mut)]
-        pub signer_reward: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            constraint = stake.owner == signer.key() @ FarmError::WrongSigner,
+        )]
+        pub stake: Box<Account<'info, dot::program::Stake>>,
+        #[account(
+            mut,
+            seeds = [b"vesting", stake.key().as_ref(), farm.archived_crop_id[usize::from(crop_index)].to_le_bytes().as_ref()],
+            bump,
+        )]
+        pub vesting: Box<Account<'info, dot::program::Vesting>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = protocol.fee_bps == 0 || treasury_reward.owner == protocol.treasury @ FarmError::WrongTreasury,
+        )]
+        pub treasury_reward: Box<Account<'info, TokenAccount>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
         #[account(mut)]
-        pub protocol: Box<Account<'info, dot::program::Protocol>>,
-        #[account(mut)]
         pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
         pub token_program: Program<'info, Token>,
     }
 
-    pub fn remove_crop(ctx: Context<RemoveCrop>, crop_index: u8) -> Result<()> {
+    pub fn vest_archived_rewards(ctx: Context<VestArchivedRewards>, crop_index: u8) -> Result<()> {
         let mut programs = HashMap::new();
 
         programs.insert(
@@ -553,8 +2175,10 @@ mod farm {
             programs: &programs_map,
         };
 
-        let signer_reward = SeahorseAccount {
-            account: &ctx.accounts.signer_reward,
+        let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
+        let vesting = dot::program::Vesting::load(&mut ctx.accounts.vesting, &programs_map);
+        let treasury_reward = SeahorseAccount {
+            account: &ctx.accounts.treasury_reward,
             programs: &programs_map,
         };
 
@@ -565,42 +2189,81 @@ mod farm {
             programs: &programs_map,
         };
 
-        remove_crop_handler(
-            farm.clone(),
+        vest_archived_rewards_handler(
             crop_index,
+            farm.clone(),
             crop_vault.clone(),
-            signer_reward.clone(),
+            stake.clone(),
+            vesting.clone(),
+            treasury_reward.clone(),
             clock.clone(),
-            protocol.clone(),
             signer.clone(),
-        );
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+        )?;
 
         dot::program::Farm::store(farm);
 
-        dot::program::Protocol::store(protocol);
+        dot::program::Stake::store(stake);
+
+        dot::program::Vesting::store(vesting);
 
         return Ok(());
     }
 
     #[derive(Accounts)]
-    # [instruction (amount : u64)]
-    pub struct StakeTokens<'info> {
-        #[account(mut)]
+    # [instruction (crop_index : u8)]
+    pub struct VestRewards<'info> {
+        #[account(
+            mut,
+            constraint = usize::from(crop_index) < accrual::CROP_COUNT @ FarmError::IndexTooHigh,
+            constraint = !farm.paused @ FarmError::FarmPaused,
+            constraint = farm.crop_vesting_period[usize::from(crop_index)] > 0 @ FarmError::RewardsDoNotVest,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
-        #[account(mut)]
-        pub stake_vault: Box<Account<'info, TokenAccount>>,
-        #[account(mut)]
+        #[account(
+            mut,
+            constraint = crop_vault.key() == farm.crop_vault[usize::from(crop_index)] @ FarmError::WrongCropVault,
+            token::authority = farm_authority,
+        )]
+        pub crop_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), stake.owner.as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+            constraint = stake.owner == signer.key() @ FarmError::WrongSigner,
+        )]
         pub stake: Box<Account<'info, dot::program::Stake>>,
-        #[account(mut)]
-        pub signer_token: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"vesting", stake.key().as_ref(), farm.crop_id[usize::from(crop_index)].to_le_bytes().as_ref()],
+            bump,
+        )]
+        pub vesting: Box<Account<'info, dot::program::Vesting>>,
+        #[account(
+            mut,
+            token::mint = crop_vault.mint,
+            constraint = protocol.fee_bps == 0 || treasury_reward.owner == protocol.treasury @ FarmError::WrongTreasury,
+        )]
+        pub treasury_reward: Box<Account<'info, TokenAccount>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
         #[account(mut)]
         pub signer: Signer<'info>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
+        #[account(
+            seeds = [b"protocol"],
+            bump = protocol.bump_seed,
+            constraint = !protocol.paused @ FarmError::ProtocolPaused,
+        )]
+        pub protocol: Box<Account<'info, dot::program::Protocol>>,
         pub token_program: Program<'info, Token>,
     }
 
-    pub fn stake_tokens(ctx: Context<StakeTokens>, amount: u64) -> Result<()> {
+    pub fn vest_rewards(ctx: Context<VestRewards>, crop_index: u8) -> Result<()> {
         let mut programs = HashMap::new();
 
         programs.insert(
@@ -610,61 +2273,82 @@ mod farm {
 
         let programs_map = ProgramsMap(programs);
         let farm = dot::program::Farm::load(&mut ctx.accounts.farm, &programs_map);
-        let stake_vault = SeahorseAccount {
-            account: &ctx.accounts.stake_vault,
+        let crop_vault = SeahorseAccount {
+            account: &ctx.accounts.crop_vault,
             programs: &programs_map,
         };
 
         let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
-        let signer_token = SeahorseAccount {
-            account: &ctx.accounts.signer_token,
+        let vesting = dot::program::Vesting::load(&mut ctx.accounts.vesting, &programs_map);
+        let treasury_reward = SeahorseAccount {
+            account: &ctx.accounts.treasury_reward,
             programs: &programs_map,
         };
 
         let clock = &ctx.accounts.clock.clone();
+        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
         let signer = SeahorseSigner {
             account: &ctx.accounts.signer,
             programs: &programs_map,
         };
 
-        stake_tokens_handler(
-            amount,
+        vest_rewards_handler(
+            crop_index,
             farm.clone(),
-            stake_vault.clone(),
+            crop_vault.clone(),
             stake.clone(),
-            signer_token.clone(),
+            vesting.clone(),
+            treasury_reward.clone(),
             clock.clone(),
             signer.clone(),
-        );
+            ctx.accounts.farm_authority.to_account_info(),
+            protocol.clone(),
+        )?;
 
         dot::program::Farm::store(farm);
 
         dot::program::Stake::store(stake);
 
+        dot::program::Vesting::store(vesting);
+
         return Ok(());
     }
 
     #[derive(Accounts)]
-    # [instruction (amount : u64)]
-    pub struct UnstakeTokens<'info> {
-        #[account(mut)]
+    pub struct WithdrawUnbonded<'info> {
+        #[account(
+            mut,
+            has_one = stake_vault @ FarmError::WrongStakeVault,
+        )]
         pub farm: Box<Account<'info, dot::program::Farm>>,
         #[account(mut)]
         pub stake_vault: Box<Account<'info, TokenAccount>>,
-        #[account(mut)]
+        #[account(mut, seeds = [b"farm-fee-vault", farm.key().as_ref()], bump)]
+        pub fee_vault: Box<Account<'info, TokenAccount>>,
+        #[account(
+            mut,
+            seeds = [b"stake", farm.key().as_ref(), signer.key().as_ref()],
+            bump,
+            has_one = farm @ FarmError::WrongFarm,
+        )]
         pub stake: Box<Account<'info, dot::program::Stake>>,
-        #[account(mut)]
+        #[account(
+            mut,
+            token::mint = farm.stake_mint,
+            token::authority = signer,
+        )]
         pub signer_token: Box<Account<'info, TokenAccount>>,
         #[account()]
         pub clock: Sysvar<'info, Clock>,
-        #[account(mut)]
-        pub protocol: Box<Account<'info, dot::program::Protocol>>,
+        #[doc = "CHECK: Holds no data; it only signs for the farm's vaults."]
+        #[account(seeds = [b"farm-authority", farm.key().as_ref()], bump = farm.authority_bump)]
+        pub farm_authority: UncheckedAccount<'info>,
         #[account(mut)]
         pub signer: Signer<'info>,
         pub token_program: Program<'info, Token>,
     }
 
-    pub fn unstake_tokens(ctx: Context<UnstakeTokens>, amount: u64) -> Result<()> {
+    pub fn withdraw_unbonded(ctx: Context<WithdrawUnbonded>) -> Result<()> {
         let mut programs = HashMap::new();
 
         programs.insert(
@@ -679,6 +2363,11 @@ mod farm {
             programs: &programs_map,
         };
 
+        let fee_vault = SeahorseAccount {
+            account: &ctx.accounts.fee_vault,
+            programs: &programs_map,
+        };
+
         let stake = dot::program::Stake::load(&mut ctx.accounts.stake, &programs_map);
         let signer_token = SeahorseAccount {
             account: &ctx.accounts.signer_token,
@@ -686,29 +2375,26 @@ mod farm {
         };
 
         let clock = &ctx.accounts.clock.clone();
-        let protocol = dot::program::Protocol::load(&mut ctx.accounts.protocol, &programs_map);
         let signer = SeahorseSigner {
             account: &ctx.accounts.signer,
             programs: &programs_map,
         };
 
-        unstake_tokens_handler(
-            amount,
+        withdraw_unbonded_handler(
             farm.clone(),
             stake_vault.clone(),
+            fee_vault.clone(),
             stake.clone(),
             signer_token.clone(),
             clock.clone(),
-            protocol.clone(),
+            ctx.accounts.farm_authority.to_account_info(),
             signer.clone(),
-        );
+        )?;
 
         dot::program::Farm::store(farm);
 
         dot::program::Stake::store(stake);
 
-        dot::program::Protocol::store(protocol);
-
         return Ok(());
     }
 }
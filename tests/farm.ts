@@ -7,6 +7,7 @@ import {
   getOrCreateAssociatedTokenAccount,
   TOKEN_PROGRAM_ID,
   getAssociatedTokenAddress,
+  getAccount,
 } from '@solana/spl-token';
 
 import {
@@ -49,8 +50,13 @@ describe('farm', () => {
   let userTwoReward: PublicKey;
 
   it('Creates a protocol', async () => {
-    // Add your test here.
-    const method = program.methods.createProtocol();
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111'),
+    );
+    const method = program.methods
+      .createProtocol()
+      .accounts({ program: program.programId, programData });
     const keys = await method.pubkeys();
     protocol = keys.protocol;
     const tx = await method.rpc();
@@ -66,7 +72,7 @@ describe('farm', () => {
     );
 
     const createFarm = await program.methods
-      .createFarm()
+      .createFarm(0, 0)
       .accounts({
         stakeMint,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
@@ -109,19 +115,19 @@ describe('farm', () => {
       .createCropVault()
       .accounts({
         farm,
-        protocol,
         mint: rewardMintOne,
       })
       .rpcAndKeys();
 
     cropVault = createCropVault.pubkeys.cropVault;
     const createCrop = await program.methods
-      .addCrop(0, new BN(1000), new BN(100))
+      .addCrop(0, new BN(1000), new BN(100), null, new BN(0))
       .accounts({
         farm,
         signerReward: walletReward,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         cropVault,
+        protocol,
       })
       .rpc();
   });
@@ -160,6 +166,25 @@ describe('farm', () => {
     userOneStake = createStake.pubkeys.stake;
   });
 
+  it('Rejects crop creation from a non-owner', async () => {
+    try {
+      await userOneProgram.methods
+        .addCrop(1, new BN(1000), new BN(100), null, new BN(0))
+        .accounts({
+          farm,
+          signerReward: userOneReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          cropVault,
+          protocol,
+        })
+        .rpc();
+      assert.fail('addCrop should reject a non-owner signer');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+  });
+
   it('Stakes tokens for single stake', async () => {
     userOneStakeMint = (
       await getOrCreateAssociatedTokenAccount(
@@ -180,7 +205,7 @@ describe('farm', () => {
     );
 
     const stakeTokens = await userOneProgram.methods
-      .stakeTokens(new BN(100000))
+      .stakeTokens(new BN(100000), 0)
       .accounts({
         farm,
         stake: userOneStake,
@@ -189,6 +214,9 @@ describe('farm', () => {
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
       })
       .rpc();
+
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.stakers.toNumber(), 1);
   });
 
   it('Unstakes tokens for single stake', async () => {
@@ -202,7 +230,6 @@ describe('farm', () => {
         signerToken: userOneStakeMint,
         stakeVault,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
-        protocol,
       })
       .rpc();
 
@@ -212,6 +239,8 @@ describe('farm', () => {
       );
 
     const stakeAccount = await program.account.stake.fetch(userOneStake);
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.stakers.toNumber(), 0);
 
     // assert.strictEqual(0, stakeAccount.totalAmountWithdrawn[0].toNumber());
     // assert.strictEqual(stakeBalance.value.amount, '100000000');
@@ -225,6 +254,7 @@ describe('farm', () => {
         cropVault,
         stake: userOneStake,
         signerReward: userOneReward,
+        treasuryReward: walletReward,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         protocol,
       })
@@ -244,14 +274,13 @@ describe('farm', () => {
         cropVault,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         signerReward: walletReward,
-        protocol,
       })
       .rpc();
   });
 
   it('Stakes to farm before crop creation with two users', async () => {
     const stakeTokens = await userOneProgram.methods
-      .stakeTokens(new BN(100000))
+      .stakeTokens(new BN(100000), 0)
       .accounts({
         farm,
         stake: userOneStake,
@@ -303,7 +332,7 @@ describe('farm', () => {
     );
 
     const userTwoStakeTokens = await userTwoProgram.methods
-      .stakeTokens(new BN(100000))
+      .stakeTokens(new BN(100000), 0)
       .accounts({
         farm,
         stake: userTwoStake,
@@ -317,6 +346,25 @@ describe('farm', () => {
     assert.strictEqual(farmAccount.totalStakedAmount.toString(), '200000');
   });
 
+  it("Rejects staking into another user's stake account", async () => {
+    try {
+      await userTwoProgram.methods
+        .stakeTokens(new BN(100000), 0)
+        .accounts({
+          farm,
+          stake: userOneStake,
+          signerToken: userTwoStakeMint,
+          stakeVault,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        })
+        .rpc();
+      assert.fail("stakeTokens should reject another user's stake");
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'ConstraintSeeds');
+    }
+  });
+
   it('Creates a new crop at the same index with a new mint', async () => {
     rewardMintOne = await createMint(
       program.provider.connection,
@@ -348,19 +396,19 @@ describe('farm', () => {
       .createCropVault()
       .accounts({
         farm,
-        protocol,
         mint: rewardMintOne,
       })
       .rpcAndKeys();
 
     cropVault = createCropVault.pubkeys.cropVault;
     const createCrop = await program.methods
-      .addCrop(0, new BN(1000), new BN(100))
+      .addCrop(0, new BN(1000), new BN(100), null, new BN(0))
       .accounts({
         farm,
         signerReward: walletReward,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         cropVault,
+        protocol,
       })
       .rpc();
   });
@@ -393,6 +441,7 @@ describe('farm', () => {
         cropVault,
         stake: userOneStake,
         signerReward: userOneReward,
+        treasuryReward: walletReward,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         protocol,
       })
@@ -405,6 +454,7 @@ describe('farm', () => {
         cropVault,
         stake: userTwoStake,
         signerReward: userTwoReward,
+        treasuryReward: walletReward,
         clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
         protocol,
       })
@@ -423,4 +473,1123 @@ describe('farm', () => {
     assert.strictEqual(userOneRewardBalance.value.amount, '499');
     assert.strictEqual(userTwoRewardBalance.value.amount, '499');
   });
+
+  it('Rejects topping up a crop that has ended', async () => {
+    try {
+      await program.methods
+        .fundCrop(0, new BN(1000), null)
+        .accounts({
+          farm,
+          cropVault,
+          signerReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail('fundCrop should reject a crop that has ended');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'CropEnded');
+    }
+  });
+
+  it('Schedules a crop to start later', async () => {
+    const startAt = Math.floor(Date.now() / 1000) + 3600;
+
+    await program.methods
+      .addCrop(1, new BN(1000), new BN(100), new BN(startAt), new BN(0))
+      .accounts({
+        farm,
+        signerReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        cropVault,
+        protocol,
+      })
+      .rpc();
+
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.cropStartAt[1].toNumber(), startAt);
+    assert.strictEqual(farmAccount.cropEndDate[1].toNumber(), startAt + 10);
+    assert.isBelow(farmAccount.cropCreatedAt[1].toNumber(), startAt);
+  });
+
+  it('Uses every crop slot and rejects an index past the last', async () => {
+    await program.methods
+      .addCrop(7, new BN(1000), new BN(100), null, new BN(0))
+      .accounts({
+        farm,
+        signerReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        cropVault,
+        protocol,
+      })
+      .rpc();
+
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.isAbove(farmAccount.cropCreatedAt[7].toNumber(), 0);
+
+    try {
+      await program.methods
+        .addCrop(8, new BN(1000), new BN(100), null, new BN(0))
+        .accounts({
+          farm,
+          signerReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          cropVault,
+          protocol,
+        })
+        .rpc();
+      assert.fail('addCrop should reject an index past the last slot');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'IndexTooHigh');
+    }
+  });
+
+  it('Gathers every active crop in one instruction', async () => {
+    const farmAccount = await program.account.farm.fetch(farm);
+    const activeCrops = farmAccount.cropCreatedAt.filter(
+      createdAt => createdAt.toNumber() !== 0,
+    ).length;
+    const cropPairs = (vault: PublicKey) =>
+      Array.from({ length: activeCrops }).flatMap(() => [
+        { pubkey: vault, isWritable: true, isSigner: false },
+        { pubkey: userOneReward, isWritable: true, isSigner: false },
+        { pubkey: walletReward, isWritable: true, isSigner: false },
+      ]);
+
+    try {
+      await userOneProgram.methods
+        .gatherAllRewards()
+        .accounts({
+          farm,
+          stake: userOneStake,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .remainingAccounts(cropPairs(stakeVault))
+        .rpc();
+      assert.fail(
+        'gatherAllRewards should reject a vault that is not the crop vault',
+      );
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongCropVault');
+    }
+
+    await userOneProgram.methods
+      .gatherAllRewards()
+      .accounts({
+        farm,
+        stake: userOneStake,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .remainingAccounts(cropPairs(cropVault))
+      .rpc();
+
+    const stakeAccount = await program.account.stake.fetch(userOneStake);
+    stakeAccount.amountOwed.forEach(owed =>
+      assert.strictEqual(owed.toNumber(), 0),
+    );
+  });
+
+  it("Pays owner-initiated claims to the stake owner's account", async () => {
+    try {
+      await program.methods
+        .gatherRewards(0)
+        .accounts({
+          farm,
+          cropVault,
+          stake: userOneStake,
+          signerReward: walletReward,
+          treasuryReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail(
+        "gatherRewards should not pay a staker's rewards to the farm owner",
+      );
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongRewardRecipient');
+    }
+
+    await program.methods
+      .gatherRewards(0)
+      .accounts({
+        farm,
+        cropVault,
+        stake: userOneStake,
+        signerReward: userOneReward,
+        treasuryReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .rpc();
+  });
+
+  it('Pays rewards to the reward recipient set on a stake', async () => {
+    await userTwoProgram.methods
+      .setRewardRecipient(wallet.publicKey)
+      .accounts({
+        farm,
+        stake: userTwoStake,
+      })
+      .rpc();
+
+    const stakeAccount = await program.account.stake.fetch(userTwoStake);
+    assert.isTrue(stakeAccount.rewardRecipient.equals(wallet.publicKey));
+
+    await userTwoProgram.methods
+      .gatherRewards(0)
+      .accounts({
+        farm,
+        cropVault,
+        stake: userTwoStake,
+        signerReward: walletReward,
+        treasuryReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .rpc();
+
+    try {
+      await userTwoProgram.methods
+        .gatherRewards(0)
+        .accounts({
+          farm,
+          cropVault,
+          stake: userTwoStake,
+          signerReward: userTwoReward,
+          treasuryReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail('gatherRewards should only pay the reward recipient');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongRewardRecipient');
+    }
+  });
+
+  it('Closes an empty stake and returns its rent', async () => {
+    const balanceBefore = await program.provider.connection.getBalance(
+      userOne.publicKey,
+    );
+
+    await userOneProgram.methods
+      .closeStake()
+      .accounts({
+        farm,
+        stake: userOneStake,
+      })
+      .rpc();
+
+    const stakeAccount = await program.account.stake.fetchNullable(
+      userOneStake,
+    );
+    assert.isNull(stakeAccount);
+
+    const balanceAfter = await program.provider.connection.getBalance(
+      userOne.publicKey,
+    );
+    assert.isAbove(balanceAfter, balanceBefore);
+  });
+
+  it('Rejects closing a farm that is still in use', async () => {
+    const walletStakeToken = (
+      await getOrCreateAssociatedTokenAccount(
+        program.provider.connection,
+        wallet,
+        stakeMint,
+        wallet.publicKey,
+      )
+    ).address;
+
+    // Both crop vaults have to be closed along with the farm
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.cropVaults.toNumber(), 2);
+
+    try {
+      await program.methods
+        .closeFarm()
+        .accounts({
+          farm,
+          stakeVault,
+          signerToken: walletStakeToken,
+        })
+        .remainingAccounts([
+          { pubkey: cropVault, isWritable: true, isSigner: false },
+          { pubkey: walletReward, isWritable: true, isSigner: false },
+        ])
+        .rpc();
+      assert.fail('closeFarm should reject a farm that is still in use');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'FarmNotEmpty');
+    }
+  });
+
+  it('Hands the farm to a new owner once they accept', async () => {
+    await program.methods
+      .proposeOwner(userTwo.publicKey)
+      .accounts({ farm })
+      .rpc();
+
+    let farmAccount = await program.account.farm.fetch(farm);
+    assert.isTrue(farmAccount.owner.equals(wallet.publicKey));
+    assert.isTrue(farmAccount.pendingOwner.equals(userTwo.publicKey));
+
+    await userTwoProgram.methods.acceptOwner().accounts({ farm }).rpc();
+
+    farmAccount = await program.account.farm.fetch(farm);
+    assert.isTrue(farmAccount.owner.equals(userTwo.publicKey));
+    assert.isTrue(farmAccount.pendingOwner.equals(PublicKey.default));
+
+    try {
+      await program.methods
+        .proposeOwner(wallet.publicKey)
+        .accounts({ farm })
+        .rpc();
+      assert.fail('proposeOwner should reject the previous owner');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+
+    await userTwoProgram.methods
+      .proposeOwner(wallet.publicKey)
+      .accounts({ farm })
+      .rpc();
+    await program.methods.acceptOwner().accounts({ farm }).rpc();
+  });
+
+  it('Lets stakers withdraw principal while the farm is paused', async () => {
+    await program.methods.pauseFarm(true).accounts({ farm }).rpc();
+
+    try {
+      await userTwoProgram.methods
+        .stakeTokens(new BN(1), 0)
+        .accounts({
+          farm,
+          stake: userTwoStake,
+          signerToken: userTwoStakeMint,
+          stakeVault,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        })
+        .rpc();
+      assert.fail('stakeTokens should be blocked while the farm is paused');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'FarmPaused');
+    }
+
+    const stakeBefore = await program.account.stake.fetch(userTwoStake);
+    const balanceBefore =
+      await program.provider.connection.getTokenAccountBalance(
+        userTwoStakeMint,
+      );
+
+    await userTwoProgram.methods
+      .emergencyUnstake()
+      .accounts({
+        farm,
+        stakeVault,
+        stake: userTwoStake,
+        signerToken: userTwoStakeMint,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .rpc();
+
+    const balanceAfter =
+      await program.provider.connection.getTokenAccountBalance(
+        userTwoStakeMint,
+      );
+    assert.strictEqual(
+      Number(balanceAfter.value.amount) - Number(balanceBefore.value.amount),
+      stakeBefore.amountStaked.toNumber(),
+    );
+
+    const stakeAfter = await program.account.stake.fetch(userTwoStake);
+    assert.strictEqual(stakeAfter.amountStaked.toNumber(), 0);
+    stakeAfter.amountOwed.forEach(owed =>
+      assert.strictEqual(owed.toNumber(), 0),
+    );
+
+    await program.methods.pauseFarm(false).accounts({ farm }).rpc();
+  });
+
+  it('Only lets the protocol admin change global settings', async () => {
+    const protocolAccount = await program.account.protocol.fetch(protocol);
+    assert.isTrue(protocolAccount.admin.equals(wallet.publicKey));
+    const mints = Array(8).fill(PublicKey.default);
+
+    try {
+      await userOneProgram.methods
+        .updateProtocol(userOne.publicKey, true, 0, PublicKey.default, mints)
+        .accounts({ protocol })
+        .rpc();
+      assert.fail('updateProtocol should reject a non-admin signer');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+
+    try {
+      await program.methods
+        .updateProtocol(
+          wallet.publicKey,
+          false,
+          2501,
+          PublicKey.default,
+          mints,
+        )
+        .accounts({ protocol })
+        .rpc();
+      assert.fail('updateProtocol should reject a fee above the cap');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'InvalidFee');
+    }
+
+    await program.methods
+      .updateProtocol(wallet.publicKey, true, 0, PublicKey.default, mints)
+      .accounts({ protocol })
+      .rpc();
+
+    try {
+      await userOneProgram.methods
+        .stakeTokens(new BN(1), 0)
+        .accounts({
+          farm,
+          stake: userOneStake,
+          signerToken: userOneStakeMint,
+          stakeVault,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        })
+        .rpc();
+      assert.fail('stakeTokens should be blocked while the protocol is paused');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'ProtocolPaused');
+    }
+
+    await program.methods
+      .updateProtocol(wallet.publicKey, false, 0, PublicKey.default, mints)
+      .accounts({ protocol })
+      .rpc();
+  });
+
+  it('Holds each farm vault under a farm-scoped authority', async () => {
+    const [farmAuthority] = PublicKey.findProgramAddressSync(
+      [Buffer.from('farm-authority'), farm.toBuffer()],
+      program.programId,
+    );
+    const connection = program.provider.connection;
+
+    const stakeVaultAccount = await getAccount(connection, stakeVault);
+    assert.isTrue(stakeVaultAccount.owner.equals(farmAuthority));
+
+    const cropVaultAccount = await getAccount(connection, cropVault);
+    assert.isTrue(cropVaultAccount.owner.equals(farmAuthority));
+    assert.isFalse(cropVaultAccount.owner.equals(protocol));
+  });
+
+  it('Routes the protocol fee on rewards to the treasury', async () => {
+    const mints = Array(8).fill(PublicKey.default);
+    await program.methods
+      .updateProtocol(wallet.publicKey, false, 2500, wallet.publicKey, mints)
+      .accounts({ protocol })
+      .rpc();
+
+    const gather = (treasuryReward: PublicKey) =>
+      userOneProgram.methods
+        .gatherRewards(0)
+        .accounts({
+          farm,
+          cropVault,
+          stake: userOneStake,
+          signerReward: userOneReward,
+          treasuryReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+
+    try {
+      await gather(userOneReward);
+      assert.fail('gatherRewards should only pay fees to the treasury');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongTreasury');
+    }
+
+    const connection = program.provider.connection;
+    const balance = async (account: PublicKey) =>
+      Number((await connection.getTokenAccountBalance(account)).value.amount);
+    const feesBefore = (await program.account.farm.fetch(farm)).cropFees[0];
+    const stakerBefore = await balance(userOneReward);
+    const treasuryBefore = await balance(walletReward);
+
+    await gather(walletReward);
+
+    const stakerPaid = (await balance(userOneReward)) - stakerBefore;
+    const feePaid = (await balance(walletReward)) - treasuryBefore;
+    assert.strictEqual(
+      feePaid,
+      Math.floor(((stakerPaid + feePaid) * 25) / 100),
+    );
+
+    const feesAfter = (await program.account.farm.fetch(farm)).cropFees[0];
+    assert.strictEqual(feesAfter.sub(feesBefore).toNumber(), feePaid);
+
+    // Payouts from an archived crop record their fee against its slot too
+    await program.methods
+      .addCrop(3, new BN(100), new BN(100), null, new BN(0))
+      .accounts({
+        farm,
+        signerReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        cropVault,
+        protocol,
+      })
+      .rpc();
+
+    await new Promise(r => setTimeout(r, 2000));
+
+    await program.methods
+      .removeCrop(3)
+      .accounts({
+        farm,
+        cropVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        signerReward: walletReward,
+      })
+      .rpc();
+
+    const archivedBefore = await balance(walletReward);
+
+    await userOneProgram.methods
+      .gatherArchivedRewards(3)
+      .accounts({
+        farm,
+        cropVault,
+        stake: userOneStake,
+        signerReward: userOneReward,
+        treasuryReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .rpc();
+
+    const archivedFee = (await balance(walletReward)) - archivedBefore;
+    assert.isAbove(archivedFee, 0);
+    const archived = await program.account.farm.fetch(farm);
+    assert.strictEqual(archived.archivedCropFees[3].toNumber(), archivedFee);
+
+    await program.methods
+      .updateProtocol(wallet.publicKey, false, 0, wallet.publicKey, mints)
+      .accounts({ protocol })
+      .rpc();
+  });
+
+  it('Skims deposit and withdrawal fees into the fee vault', async () => {
+    const [feeVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from('farm-fee-vault'), farm.toBuffer()],
+      program.programId,
+    );
+
+    try {
+      await program.methods
+        .setFarmFees(1001, 0)
+        .accounts({ farm })
+        .rpc();
+      assert.fail('setFarmFees should reject a fee above the maximum');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'InvalidFee');
+    }
+
+    await program.methods.setFarmFees(100, 200).accounts({ farm }).rpc();
+
+    const connection = program.provider.connection;
+    const balance = async (account: PublicKey) =>
+      Number((await connection.getTokenAccountBalance(account)).value.amount);
+    const stakeBefore = await program.account.stake.fetch(userOneStake);
+
+    await userOneProgram.methods
+      .stakeTokens(new BN(10000), 0)
+      .accounts({
+        farm,
+        stake: userOneStake,
+        signerToken: userOneStakeMint,
+        stakeVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      })
+      .rpc();
+
+    const stakeAfter = await program.account.stake.fetch(userOneStake);
+    assert.strictEqual(
+      stakeAfter.amountStaked.sub(stakeBefore.amountStaked).toNumber(),
+      9900,
+    );
+    assert.strictEqual(await balance(feeVault), 100);
+
+    const userBefore = await balance(userOneStakeMint);
+
+    await userOneProgram.methods
+      .unstakeTokens(new BN(1000))
+      .accounts({
+        farm,
+        stake: userOneStake,
+        signerToken: userOneStakeMint,
+        stakeVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      })
+      .rpc();
+
+    assert.strictEqual((await balance(userOneStakeMint)) - userBefore, 980);
+    assert.strictEqual(await balance(feeVault), 120);
+
+    const walletStakeToken = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        wallet,
+        stakeMint,
+        wallet.publicKey,
+      )
+    ).address;
+    const walletBefore = await balance(walletStakeToken);
+
+    await program.methods
+      .collectFarmFees()
+      .accounts({ farm, signerToken: walletStakeToken })
+      .rpc();
+
+    assert.strictEqual((await balance(walletStakeToken)) - walletBefore, 120);
+    assert.strictEqual(await balance(feeVault), 0);
+
+    await program.methods.setFarmFees(0, 0).accounts({ farm }).rpc();
+  });
+
+  it('Weights locked stakes and holds them until the lock ends', async () => {
+    const stakeAccounts = {
+      farm,
+      stake: userTwoStake,
+      signerToken: userTwoStakeMint,
+      stakeVault,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+    const farmBefore = await program.account.farm.fetch(farm);
+
+    await userTwoProgram.methods
+      .stakeTokens(new BN(1000), 1)
+      .accounts(stakeAccounts)
+      .rpc();
+
+    const stakeAccount = await program.account.stake.fetch(userTwoStake);
+    assert.strictEqual(stakeAccount.lockTier, 1);
+    assert.strictEqual(stakeAccount.weightedStake.toNumber(), 1250);
+
+    const farmAfter = await program.account.farm.fetch(farm);
+    const weightAdded = farmAfter.totalWeightedStake.sub(
+      farmBefore.totalWeightedStake,
+    );
+    assert.strictEqual(weightAdded.toNumber(), 1250);
+
+    try {
+      await userTwoProgram.methods
+        .unstakeTokens(new BN(1000))
+        .accounts(stakeAccounts)
+        .rpc();
+      assert.fail('unstakeTokens should wait for the lock to end');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'StakeLocked');
+    }
+
+    try {
+      await userTwoProgram.methods
+        .stakeTokens(new BN(1000), 0)
+        .accounts(stakeAccounts)
+        .rpc();
+      assert.fail('stakeTokens should not shorten an active lock');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'LockTooShort');
+    }
+
+    await program.methods.pauseFarm(true).accounts({ farm }).rpc();
+    try {
+      await userTwoProgram.methods
+        .emergencyUnstake()
+        .accounts({
+          farm,
+          stakeVault,
+          stake: userTwoStake,
+          signerToken: userTwoStakeMint,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail('a farm pause should not release a locked stake');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'StakeLocked');
+    }
+    await program.methods.pauseFarm(false).accounts({ farm }).rpc();
+
+    await userOneProgram.methods
+      .refreshStake()
+      .accounts({
+        farm,
+        stake: userTwoStake,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      })
+      .rpc();
+    const refreshed = await program.account.stake.fetch(userTwoStake);
+    assert.strictEqual(refreshed.weightedStake.toNumber(), 1250);
+  });
+
+  it('Holds unstaked tokens until the unbonding period ends', async () => {
+    const stakeAccounts = {
+      farm,
+      stake: userOneStake,
+      signerToken: userOneStakeMint,
+      stakeVault,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+
+    try {
+      await userOneProgram.methods
+        .setUnbondingPeriod(new BN(3))
+        .accounts({ farm })
+        .rpc();
+      assert.fail('setUnbondingPeriod should be limited to the farm owner');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+
+    try {
+      await program.methods
+        .setUnbondingPeriod(new BN(30 * 24 * 60 * 60 + 1))
+        .accounts({ farm })
+        .rpc();
+      assert.fail('setUnbondingPeriod should be capped');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'UnbondingPeriodTooLong');
+    }
+
+    await program.methods
+      .setUnbondingPeriod(new BN(3))
+      .accounts({ farm })
+      .rpc();
+
+    const connection = program.provider.connection;
+    const balance = async (account: PublicKey) =>
+      Number((await connection.getTokenAccountBalance(account)).value.amount);
+    const userBefore = await balance(userOneStakeMint);
+    const stakeBefore = await program.account.stake.fetch(userOneStake);
+
+    await program.methods.setFarmFees(0, 200).accounts({ farm }).rpc();
+
+    await userOneProgram.methods
+      .unstakeTokens(new BN(500))
+      .accounts(stakeAccounts)
+      .rpc();
+
+    // Raising the fee afterwards does not reach tokens already unbonding
+    await program.methods.setFarmFees(0, 1000).accounts({ farm }).rpc();
+
+    assert.strictEqual(await balance(userOneStakeMint), userBefore);
+    const stakeAfter = await program.account.stake.fetch(userOneStake);
+    assert.strictEqual(
+      stakeBefore.amountStaked.sub(stakeAfter.amountStaked).toNumber(),
+      500,
+    );
+    assert.strictEqual(stakeAfter.unbondingAmount[0].toNumber(), 500);
+    assert.strictEqual(stakeAfter.unbondingFee[0].toNumber(), 10);
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.totalUnbonding.toNumber(), 500);
+
+    try {
+      await userOneProgram.methods
+        .withdrawUnbonded()
+        .accounts(stakeAccounts)
+        .rpc();
+      assert.fail('withdrawUnbonded should wait for the period to end');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'StillUnbonding');
+    }
+
+    await new Promise(r => setTimeout(r, 4000));
+
+    await userOneProgram.methods
+      .withdrawUnbonded()
+      .accounts(stakeAccounts)
+      .rpc();
+
+    assert.strictEqual((await balance(userOneStakeMint)) - userBefore, 490);
+    const stakeWithdrawn = await program.account.stake.fetch(userOneStake);
+    assert.strictEqual(stakeWithdrawn.unbondingAmount[0].toNumber(), 0);
+
+    try {
+      await userOneProgram.methods
+        .withdrawUnbonded()
+        .accounts(stakeAccounts)
+        .rpc();
+      assert.fail('withdrawUnbonded should need something unbonding');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'NothingUnbonding');
+    }
+
+    await program.methods.setFarmFees(0, 0).accounts({ farm }).rpc();
+    await program.methods
+      .setUnbondingPeriod(new BN(0))
+      .accounts({ farm })
+      .rpc();
+  });
+
+  it('Keeps the deadline of tokens already unbonding', async () => {
+    const stakeAccounts = {
+      farm,
+      stake: userOneStake,
+      signerToken: userOneStakeMint,
+      stakeVault,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+    const unstake = (amount: number) =>
+      userOneProgram.methods
+        .unstakeTokens(new BN(amount))
+        .accounts(stakeAccounts)
+        .rpc();
+    const withdraw = () =>
+      userOneProgram.methods.withdrawUnbonded().accounts(stakeAccounts).rpc();
+
+    const connection = program.provider.connection;
+    const balance = async (account: PublicKey) =>
+      Number((await connection.getTokenAccountBalance(account)).value.amount);
+
+    await program.methods
+      .setUnbondingPeriod(new BN(8))
+      .accounts({ farm })
+      .rpc();
+
+    const userBefore = await balance(userOneStakeMint);
+    await unstake(100);
+    await new Promise(r => setTimeout(r, 4000));
+    await unstake(50);
+
+    // The second unstake waits out its own period without holding back
+    // the first
+    await new Promise(r => setTimeout(r, 5000));
+    await withdraw();
+
+    assert.strictEqual((await balance(userOneStakeMint)) - userBefore, 100);
+    const queued = await program.account.stake.fetch(userOneStake);
+    assert.deepEqual(
+      queued.unbondingAmount.map(amount => amount.toNumber()),
+      [0, 50, 0, 0],
+    );
+
+    await new Promise(r => setTimeout(r, 4000));
+    await withdraw();
+
+    assert.strictEqual((await balance(userOneStakeMint)) - userBefore, 150);
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.totalUnbonding.toNumber(), 0);
+
+    await program.methods
+      .setUnbondingPeriod(new BN(0))
+      .accounts({ farm })
+      .rpc();
+  });
+
+  it('Holds rewards from a vesting crop until they vest', async () => {
+    await program.methods
+      .addCrop(2, new BN(1000), new BN(100), null, new BN(4))
+      .accounts({
+        farm,
+        signerReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        cropVault,
+        protocol,
+      })
+      .rpc();
+
+    await new Promise(r => setTimeout(r, 2000));
+
+    try {
+      await userOneProgram.methods
+        .gatherRewards(2)
+        .accounts({
+          farm,
+          cropVault,
+          stake: userOneStake,
+          signerReward: userOneReward,
+          treasuryReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail('gatherRewards should not pay out a vesting crop');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'RewardsVest');
+    }
+
+    const farmAccount = await program.account.farm.fetch(farm);
+    const [vesting] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from('vesting'),
+        userOneStake.toBuffer(),
+        farmAccount.cropId[2].toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId,
+    );
+
+    await userOneProgram.methods
+      .createVesting(2, false)
+      .accounts({ farm, cropVault, stake: userOneStake, vesting })
+      .rpc();
+
+    const connection = program.provider.connection;
+    const balance = async (account: PublicKey) =>
+      Number((await connection.getTokenAccountBalance(account)).value.amount);
+    const userBefore = await balance(userOneReward);
+    const vestAccounts = {
+      farm,
+      cropVault,
+      stake: userOneStake,
+      vesting,
+      treasuryReward: walletReward,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      protocol,
+    };
+
+    try {
+      await program.methods.vestRewards(2).accounts(vestAccounts).rpc();
+      assert.fail('vestRewards should be limited to the stake owner');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+
+    await userOneProgram.methods.vestRewards(2).accounts(vestAccounts).rpc();
+
+    assert.strictEqual(await balance(userOneReward), userBefore);
+    const vested = await program.account.vesting.fetch(vesting);
+    const total = vested.vestingAmount[0].add(vested.vestedAmount).toNumber();
+    assert.isAbove(total, 0);
+
+    await new Promise(r => setTimeout(r, 5000));
+
+    const claimAccounts = {
+      farm,
+      cropVault,
+      stake: userOneStake,
+      vesting,
+      signerReward: userOneReward,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      protocol,
+    };
+
+    await userOneProgram.methods.claimVested().accounts(claimAccounts).rpc();
+
+    assert.strictEqual((await balance(userOneReward)) - userBefore, total);
+
+    try {
+      await userOneProgram.methods.claimVested().accounts(claimAccounts).rpc();
+      assert.fail('claimVested should need something vested');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'NothingVested');
+    }
+
+    await userOneProgram.methods
+      .closeVesting()
+      .accounts({ farm, stake: userOneStake, vesting })
+      .rpc();
+
+    const stakeAccount = await program.account.stake.fetch(userOneStake);
+    assert.strictEqual(stakeAccount.activeVestings.toNumber(), 0);
+  });
+
+  it('Keeps vesting the rewards of a removed crop', async () => {
+    const addCrop = (vestingPeriod: number) =>
+      program.methods
+        .addCrop(3, new BN(100), new BN(100), null, new BN(vestingPeriod))
+        .accounts({
+          farm,
+          signerReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          cropVault,
+          protocol,
+        })
+        .rpc();
+
+    try {
+      await addCrop(365 * 24 * 60 * 60 + 1);
+      assert.fail('addCrop should cap the vesting period');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'VestingPeriodTooLong');
+    }
+
+    await addCrop(4);
+    await new Promise(r => setTimeout(r, 2000));
+
+    await program.methods
+      .removeCrop(3)
+      .accounts({
+        farm,
+        cropVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        signerReward: walletReward,
+      })
+      .rpc();
+
+    const farmAccount = await program.account.farm.fetch(farm);
+    assert.strictEqual(farmAccount.archivedVestingPeriod[3].toNumber(), 4);
+
+    try {
+      await userTwoProgram.methods
+        .gatherArchivedRewards(3)
+        .accounts({
+          farm,
+          cropVault,
+          stake: userTwoStake,
+          signerReward: userTwoReward,
+          treasuryReward: walletReward,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          protocol,
+        })
+        .rpc();
+      assert.fail('gatherArchivedRewards should not pay out vesting rewards');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'RewardsVest');
+    }
+
+    const [vesting] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from('vesting'),
+        userTwoStake.toBuffer(),
+        farmAccount.archivedCropId[3].toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId,
+    );
+
+    await userTwoProgram.methods
+      .createVesting(3, true)
+      .accounts({ farm, cropVault, stake: userTwoStake, vesting })
+      .rpc();
+
+    await userTwoProgram.methods
+      .vestArchivedRewards(3)
+      .accounts({
+        farm,
+        cropVault,
+        stake: userTwoStake,
+        vesting,
+        treasuryReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        protocol,
+      })
+      .rpc();
+
+    const vested = await program.account.vesting.fetch(vesting);
+    assert.isAbove(vested.vestingAmount[0].toNumber(), 0);
+    assert.strictEqual(
+      vested.vestingUntil[0].sub(vested.vestedUntil[0]).toNumber(),
+      4,
+    );
+  });
+
+  it('Changes the emission rate of a running crop', async () => {
+    await program.methods
+      .addCrop(4, new BN(1000), new BN(100), null, new BN(0))
+      .accounts({
+        farm,
+        signerReward: walletReward,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        cropVault,
+        protocol,
+      })
+      .rpc();
+
+    try {
+      await userOneProgram.methods
+        .setCropRate(4, new BN(50))
+        .accounts({ farm, clock: anchor.web3.SYSVAR_CLOCK_PUBKEY })
+        .rpc();
+      assert.fail('setCropRate should be limited to the farm owner');
+    } catch (err) {
+      assert.instanceOf(err, anchor.AnchorError);
+      assert.strictEqual(err.error.errorCode.code, 'WrongSigner');
+    }
+
+    await new Promise(r => setTimeout(r, 2000));
+
+    await program.methods
+      .setCropRate(4, new BN(50))
+      .accounts({ farm, clock: anchor.web3.SYSVAR_CLOCK_PUBKEY })
+      .rpc();
+
+    // Everything up to the change was emitted at the old rate, and what is
+    // left now runs out at the new one
+    const farmAccount = await program.account.farm.fetch(farm);
+    const changedAt = farmAccount.cropLastUpdatedAt[4].toNumber();
+    const allocated = farmAccount.cropAllocated[4].toNumber();
+    assert.strictEqual(farmAccount.cropRewardsPerSecond[4].toNumber(), 50);
+    assert.isAbove(allocated, 0);
+    assert.strictEqual(
+      allocated,
+      100 * (changedAt - farmAccount.cropStartAt[4].toNumber()),
+    );
+    assert.strictEqual(
+      farmAccount.cropEndDate[4].toNumber(),
+      changedAt + Math.floor((1000 - allocated) / 50),
+    );
+  });
+
+  it('Migrates a farm created before crops had ids', async () => {
+    // Loaded by the test validator in the layout the first release wrote
+    const legacyFarm = new PublicKey(
+      'HWgvcWgz8dvprWNqmHXM9qG5naLAH8fGdG5LDaCMJ3yU',
+    );
+    const connection = program.provider.connection;
+
+    await program.methods.migrateFarm().accounts({ farm: legacyFarm }).rpc();
+
+    const migrated = await program.account.farm.fetch(legacyFarm);
+    assert.strictEqual(migrated.cropLastUpdatedAt[0].toNumber(), 1500);
+    assert.strictEqual(migrated.cropStartAt[0].toNumber(), 1000);
+    assert.strictEqual(migrated.cropNonce.toNumber(), 1);
+    assert.strictEqual(migrated.cropId[0].toNumber(), 1);
+    assert.strictEqual(migrated.cropFunded[0].toNumber(), 10000);
+    assert.strictEqual(migrated.cropAllocated[0].toNumber(), 5000);
+    assert.strictEqual(migrated.cropClaimed[0].toNumber(), 5000);
+    assert.strictEqual(migrated.totalWeightedStake.toNumber(), 50);
+    assert.strictEqual(migrated.cropVaults.toNumber(), 1);
+    assert.strictEqual(migrated.cropId[1].toNumber(), 0);
+    assert.strictEqual(migrated.cropStartAt[1].toNumber(), 0);
+
+    const before = await connection.getAccountInfo(legacyFarm);
+    await userOneProgram.methods
+      .migrateFarm()
+      .accounts({ farm: legacyFarm })
+      .rpc();
+    const after = await connection.getAccountInfo(legacyFarm);
+    assert.isTrue(after.data.equals(before.data));
+    assert.strictEqual(after.lamports, before.lamports);
+  });
 });